@@ -0,0 +1,123 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! LOCK ACCOUNT and UNLOCK ACCOUNT.
+//!
+//! Every kind of server keeps the lock of an account in other attributes:
+//!
+//! * OpenLDAP (ppolicy): pwdAccountLockedTime, 000001010000Z locks the
+//!   account until it is removed. pwdFailureTime is left alone, the schema
+//!   doesn't let clients change it and slapd clears it at the next
+//!   successful bind
+//! * 389-DS: nsAccountLock, and passwordRetryCount and accountUnlockTime
+//!   after too many failed binds
+//! * Active Directory: lockoutTime after too many failed binds, LOCK
+//!   disables the account with the ACCOUNTDISABLE bit of userAccountControl
+//!
+//! The locks are ordinary modifications, so they can be tried with
+//! `--dry-run` and undone like other changes.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use ldap3::{Mod, Scope, SearchEntry};
+
+use crate::modify::{Change, Operation};
+use crate::server::{Flavour, Servers};
+
+// the ACCOUNTDISABLE flag of userAccountControl
+const ACCOUNT_DISABLE: u32 = 2;
+
+/// The changes that lock or unlock the accounts of dns.
+pub fn changes(
+    servers: &mut Servers,
+    server: &str,
+    dns: Vec<String>,
+    lock: bool,
+) -> Result<Vec<Change>> {
+    let flavour = servers.flavour(server)?;
+    let mut changes = Vec::new();
+    for dn in dns {
+        let mods = match (flavour, lock) {
+            (Flavour::OpenLdap, true) => vec![replace("pwdAccountLockedTime", &["000001010000Z"])],
+            (Flavour::OpenLdap, false) => vec![replace("pwdAccountLockedTime", &[])],
+            (Flavour::Ds389, true) => vec![replace("nsAccountLock", &["TRUE"])],
+            (Flavour::Ds389, false) => vec![
+                replace("nsAccountLock", &[]),
+                replace("passwordRetryCount", &[]),
+                replace("accountUnlockTime", &[]),
+            ],
+            (Flavour::ActiveDirectory, _) => {
+                let control = user_account_control(servers, server, &dn)?;
+                let new_control = if lock {
+                    control | ACCOUNT_DISABLE
+                } else {
+                    control & !ACCOUNT_DISABLE
+                };
+                let mut mods = Vec::new();
+                if !lock {
+                    mods.push(replace("lockoutTime", &["0"]));
+                }
+                if new_control != control {
+                    mods.push(replace(
+                        "userAccountControl",
+                        &[new_control.to_string().as_str()],
+                    ));
+                }
+                mods
+            }
+            (Flavour::Other, _) => bail!(
+                "Don't know how {} locks accounts, set flavour=openldap, 389ds or ad in its section",
+                server
+            ),
+        };
+        if !mods.is_empty() {
+            changes.push(Change {
+                dn,
+                operation: Operation::Modify(mods),
+            });
+        }
+    }
+    Ok(changes)
+}
+
+// An empty list of values removes the attribute, also when it is missing.
+fn replace(attr: &str, values: &[&str]) -> Mod<String> {
+    let values: HashSet<String> = values.iter().map(|v| v.to_string()).collect();
+    Mod::Replace(attr.to_string(), values)
+}
+
+fn user_account_control(servers: &mut Servers, server: &str, dn: &str) -> Result<u32> {
+    let (entries, _) = servers
+        .ldap(server)?
+        .search(
+            dn,
+            Scope::Base,
+            "(objectClass=*)",
+            vec!["userAccountControl"],
+        )?
+        .success()
+        .context(format!("Cannot read {}", dn))?;
+    let entry = entries.into_iter().next().map(SearchEntry::construct);
+    let value = entry
+        .as_ref()
+        .and_then(|e| {
+            e.attrs
+                .iter()
+                .find(|(a, _)| a.eq_ignore_ascii_case("userAccountControl"))
+        })
+        .and_then(|(_, values)| values.first())
+        .context(format!("{} has no userAccountControl", dn))?;
+    value
+        .parse::<u32>()
+        .context(format!("Wrong userAccountControl {} of {}", value, dn))
+}
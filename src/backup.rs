@@ -0,0 +1,344 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backups of the entries a statement changes.
+//!
+//! Before the changes are made the touched attributes of every entry are
+//! read and written to a timestamped LDIF file, as the change records that
+//! undo the changes. `sqldap undo <file>` makes those changes. When an
+//! entry can't be read nothing is changed. Binary attributes (eg: jpegPhoto)
+//! can't be written back, they are left out so undo leaves them as they
+//! are. Passwords set with PASSWORD() aren't saved, but the hashes in
+//! userPassword are, so only the owner can read the file.
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use ldap3::{Mod, Scope, SearchEntry};
+
+use crate::dn;
+use crate::ldif;
+use crate::modify::{Change, Operation};
+use crate::server::Servers;
+
+/// The undo file of a statement.
+pub struct Backup {
+    path: PathBuf,
+    header: String,
+    // the changes that undo each change of the statement
+    inverses: Vec<Vec<Change>>,
+}
+
+/// Reads the entries the changes touch and writes the changes that undo
+/// them to `sqldap-<server>-<time>.ldif` in backupdir= of the server. Fails
+/// when an entry can't be read, the changes should then not be made.
+pub fn save(servers: &mut Servers, server: &str, changes: &[Change]) -> Result<Backup> {
+    let dir = servers
+        .get(server)
+        .and_then(|s| s.backupdir.clone())
+        .unwrap_or_else(|| String::from("."));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let time = timestamp(now);
+    let mut path = Path::new(&dir).join(format!("sqldap-{}-{}.ldif", server, time.0));
+    // several statements can run in the same second
+    let mut n = 1;
+    while path.exists() {
+        n += 1;
+        path = Path::new(&dir).join(format!("sqldap-{}-{}-{}.ldif", server, time.0, n));
+    }
+
+    let mut inverses = Vec::new();
+    for change in changes {
+        let inverse = inverse(servers, server, change)
+            .context(format!("Cannot back up {}, nothing was changed", change.dn))?;
+        inverses.push(inverse);
+    }
+    let backup = Backup {
+        path,
+        header: format!(
+            "# sqldap backup of {} changes on {}, {} UTC\n# server: {}\n# undo with: sqldap undo <this file>\nversion: 1\n\n",
+            changes.len(),
+            server,
+            time.1,
+            server
+        ),
+        inverses,
+    };
+    backup.write(&vec![true; changes.len()])?;
+    Ok(backup)
+}
+
+impl Backup {
+    /// Keeps the undo records of the changes that were made, done has the
+    /// result of every change. Without any, the file is removed.
+    pub fn finish(self, done: &[bool]) -> Result<()> {
+        if !done.iter().any(|d| *d) {
+            return self.discard();
+        }
+        self.write(done)?;
+        println!(
+            "The old values are saved in {}, undo the changes with: sqldap undo {}\n",
+            self.path.display(),
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    /// The changes that undo the changes that were made, the last one first.
+    pub fn undo(&self, done: &[bool]) -> Vec<Change> {
+        self.inverses
+            .iter()
+            .zip(done)
+            .rev()
+            .filter(|(_, done)| **done)
+            .flat_map(|(inverse, _)| inverse.iter().cloned())
+            .collect()
+    }
+
+    /// Removes the file, eg: when the changes were undone.
+    pub fn discard(self) -> Result<()> {
+        std::fs::remove_file(&self.path).context(format!("Cannot remove {}", self.path.display()))
+    }
+
+    // the last change is undone first
+    fn write(&self, done: &[bool]) -> Result<()> {
+        let mut text = self.header.clone();
+        for (inverse, _) in self
+            .inverses
+            .iter()
+            .zip(done)
+            .rev()
+            .filter(|(_, done)| **done)
+        {
+            for change in inverse {
+                text += &ldif::change_record(change);
+            }
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        options
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .context(format!("Cannot write the backup {}", self.path.display()))
+    }
+}
+
+/// The server a backup was made on, from its `# server:` line.
+pub fn server(text: &str) -> Option<String> {
+    text.lines()
+        .take_while(|line| line.starts_with('#'))
+        .find_map(|line| line.strip_prefix("# server: "))
+        .map(|server| server.trim().to_string())
+}
+
+// The changes that undo a change.
+fn inverse(servers: &mut Servers, server: &str, change: &Change) -> Result<Vec<Change>> {
+    match &change.operation {
+        Operation::Modify(mods) => {
+            let attrs: Vec<String> = mods
+                .iter()
+                .map(|m| match m {
+                    Mod::Add(attr, _)
+                    | Mod::Delete(attr, _)
+                    | Mod::Replace(attr, _)
+                    | Mod::Increment(attr, _) => attr.clone(),
+                })
+                .collect();
+            let entries = read(servers, server, &change.dn, Scope::Base, attrs.clone())?;
+            let old = entries.first();
+            let mut restore = Vec::new();
+            for attr in attrs {
+                if old.is_some_and(|e| binary(e).any(|a| a.eq_ignore_ascii_case(&attr))) {
+                    println!(
+                        "{} of {} has binary values, they are not in the backup",
+                        attr, change.dn
+                    );
+                    continue;
+                }
+                let values: HashSet<String> = old
+                    .and_then(|e| e.attrs.iter().find(|(a, _)| a.eq_ignore_ascii_case(&attr)))
+                    .map(|(_, values)| values.iter().cloned().collect())
+                    .unwrap_or_default();
+                if !restore
+                    .iter()
+                    .any(|m| matches!(m, Mod::Replace(a, _) if *a == attr))
+                {
+                    restore.push(Mod::Replace(attr, values));
+                }
+            }
+            Ok(vec![Change {
+                dn: change.dn.clone(),
+                operation: Operation::Modify(restore),
+            }])
+        }
+        Operation::Password(_) => Ok(Vec::new()),
+        Operation::Add(_) => Ok(vec![Change {
+            dn: change.dn.clone(),
+            operation: Operation::Delete { subtree: false },
+        }]),
+        Operation::Delete { subtree } => {
+            let scope = if *subtree {
+                Scope::Subtree
+            } else {
+                Scope::Base
+            };
+            let mut entries = read(servers, server, &change.dn, scope, vec!["*".to_string()])?;
+            // parents are added before the entries below them
+            entries.sort_by_key(|e| dn::parse(&e.dn).map(|rdns| rdns.len()).unwrap_or_default());
+            for entry in &entries {
+                let attrs: Vec<&String> = binary(entry).collect();
+                if !attrs.is_empty() {
+                    println!(
+                        "{} of {} have binary values, they are not in the backup",
+                        attrs
+                            .iter()
+                            .map(|a| a.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", "),
+                        entry.dn
+                    );
+                }
+            }
+            Ok(entries
+                .into_iter()
+                .map(|e| Change {
+                    dn: e.dn,
+                    operation: Operation::Add(
+                        e.attrs
+                            .into_iter()
+                            .map(|(attr, values)| (attr, values.into_iter().collect()))
+                            .collect(),
+                    ),
+                })
+                .collect())
+        }
+        Operation::ModifyDn {
+            rdn, new_superior, ..
+        } => {
+            let parent = dn::parent(&change.dn, 1)?;
+            let new_dn = match new_superior {
+                Some(superior) => format!("{},{}", rdn, superior),
+                None if parent.is_empty() => rdn.clone(),
+                None => format!("{},{}", rdn, parent),
+            };
+            Ok(vec![Change {
+                dn: new_dn,
+                operation: Operation::ModifyDn {
+                    rdn: dn::rdn(&change.dn)?,
+                    delete_old: true,
+                    new_superior: new_superior.as_ref().map(|_| parent),
+                },
+            }])
+        }
+    }
+}
+
+// The attributes ldap3 found binary values in, they can't be written to
+// the backup.
+fn binary(entry: &SearchEntry) -> impl Iterator<Item = &String> {
+    entry.bin_attrs.keys()
+}
+
+fn read(
+    servers: &mut Servers,
+    server: &str,
+    dn: &str,
+    scope: Scope,
+    attrs: Vec<String>,
+) -> Result<Vec<SearchEntry>> {
+    let ldap = servers.ldap(server)?;
+    let (entries, _) = ldap
+        .search(dn, scope, "(objectClass=*)", attrs)?
+        .success()
+        .context("Ldap search failed")?;
+    Ok(entries.into_iter().map(SearchEntry::construct).collect())
+}
+
+// The time as 20240131-235959 for file names and 2024-01-31 23:59:59.
+fn timestamp(secs: u64) -> (String, String) {
+    let days = (secs / 86400) as i64;
+    let (hour, min, sec) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    // the civil date of a day number (days since 1970-01-01)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            year, month, day, hour, min, sec
+        ),
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year, month, day, hour, min, sec
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(
+            timestamp(0),
+            (
+                String::from("19700101-000000"),
+                String::from("1970-01-01 00:00:00")
+            )
+        );
+        assert_eq!(timestamp(1704067199).0, "20231231-235959");
+    }
+
+    #[test]
+    fn timestamps_on_leap_days() {
+        assert_eq!(timestamp(1709251199).1, "2024-02-29 23:59:59");
+        assert_eq!(timestamp(1709251200).1, "2024-03-01 00:00:00");
+        assert_eq!(timestamp(951825600).1, "2000-02-29 12:00:00");
+        // 2100 is not a leap year
+        assert_eq!(timestamp(4107542400).1, "2100-03-01 00:00:00");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_owner_reads_a_backup() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("sqldap-test-{}.ldif", std::process::id()));
+        let backup = Backup {
+            path: path.clone(),
+            header: String::from("version: 1\n\n"),
+            inverses: vec![Vec::new()],
+        };
+        backup.write(&[true]).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        backup.discard().unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,229 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Distinguished name parsing as described in RFC 4514.
+//!
+//! Splitting a dn on ',' breaks as soon as a value contains an escaped
+//! comma (`cn=Doe\, John,ou=people,...`), so the DN_* functions go through
+//! the parser in this module instead.
+
+use anyhow::{bail, Result};
+
+/// One attribute type and value pair of an RDN, with the value unescaped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ava {
+    pub attr: String,
+    pub value: String,
+}
+
+/// A relative distinguished name, eg: `uid=jdoe` or `cn=a+sn=b`.
+pub type Rdn = Vec<Ava>;
+
+/// Parses a dn into its RDNs, the leftmost (most specific) RDN first.
+pub fn parse(dn: &str) -> Result<Vec<Rdn>> {
+    let mut rdns = Vec::new();
+    if dn.trim().is_empty() {
+        return Ok(rdns);
+    }
+
+    let mut rdn = Vec::new();
+    let mut attr = String::new();
+    let mut value: Vec<u8> = Vec::new();
+    let mut in_value = false;
+    // number of bytes at the end of value that must survive trimming
+    let mut escaped_len = 0;
+
+    let bytes = dn.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if !in_value {
+            if c == b'=' {
+                if attr.trim().is_empty() {
+                    bail!("Missing attribute type in dn '{}'", dn);
+                }
+                in_value = true;
+            } else if c == b',' || c == b'+' || c == b';' {
+                bail!("Missing '=' in dn '{}'", dn);
+            } else {
+                attr.push(c as char);
+            }
+            i += 1;
+            continue;
+        }
+
+        match c {
+            b'\\' => {
+                let next = match bytes.get(i + 1) {
+                    Some(next) => *next,
+                    None => bail!("Dangling escape character at the end of dn '{}'", dn),
+                };
+                if next.is_ascii_hexdigit()
+                    && matches!(bytes.get(i + 2), Some(b) if b.is_ascii_hexdigit())
+                {
+                    let hex = std::str::from_utf8(&bytes[i + 1..i + 3])?;
+                    value.push(u8::from_str_radix(hex, 16)?);
+                    i += 3;
+                } else {
+                    value.push(next);
+                    i += 2;
+                }
+                escaped_len = value.len();
+            }
+            b',' | b';' | b'+' => {
+                rdn.push(finish_ava(&attr, &value, escaped_len)?);
+                attr.clear();
+                value.clear();
+                escaped_len = 0;
+                in_value = false;
+                if c != b'+' {
+                    rdns.push(rdn);
+                    rdn = Vec::new();
+                }
+                i += 1;
+            }
+            // unescaped leading spaces are not part of the value
+            b' ' if value.is_empty() => i += 1,
+            _ => {
+                value.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    if !in_value {
+        bail!("Missing '=' in dn '{}'", dn);
+    }
+    rdn.push(finish_ava(&attr, &value, escaped_len)?);
+    rdns.push(rdn);
+
+    Ok(rdns)
+}
+
+fn finish_ava(attr: &str, value: &[u8], escaped_len: usize) -> Result<Ava> {
+    // neither are unescaped trailing spaces
+    let mut end = value.len();
+    while end > escaped_len && value[end - 1] == b' ' {
+        end -= 1;
+    }
+    Ok(Ava {
+        attr: attr.trim().to_string(),
+        value: String::from_utf8(value[..end].to_vec())?,
+    })
+}
+
+/// Escapes an attribute value so it can be used in a dn.
+pub fn escape_value(value: &str) -> String {
+    let mut escaped = String::new();
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn rdn_to_string(rdn: &[Ava]) -> String {
+    rdn.iter()
+        .map(|ava| format!("{}={}", ava.attr, escape_value(&ava.value)))
+        .collect::<Vec<String>>()
+        .join("+")
+}
+
+pub fn to_string(rdns: &[Rdn]) -> String {
+    rdns.iter()
+        .map(|rdn| rdn_to_string(rdn))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// DN_PART(dn, 'ou'): the value of the first (most specific) RDN
+/// component of the given attribute type.
+pub fn part(dn: &str, attr: &str) -> Result<Option<String>> {
+    for rdn in parse(dn)? {
+        for ava in rdn {
+            if ava.attr.eq_ignore_ascii_case(attr) {
+                return Ok(Some(ava.value));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// DN_PARENT(dn, n): the dn with its n leftmost RDNs removed.
+pub fn parent(dn: &str, n: usize) -> Result<String> {
+    let rdns = parse(dn)?;
+    if n >= rdns.len() {
+        return Ok(String::from(""));
+    }
+    Ok(to_string(&rdns[n..]))
+}
+
+/// DN_RDN(dn): the leftmost RDN of the dn.
+pub fn rdn(dn: &str) -> Result<String> {
+    let rdns = parse(dn)?;
+    match rdns.first() {
+        Some(rdn) => Ok(rdn_to_string(rdn)),
+        None => Ok(String::from("")),
+    }
+}
+
+/// DN_NORMALIZE(dn): the dn re-escaped in its canonical form and lowercased,
+/// so two spellings of the same dn compare equal. The values are lowercased
+/// as well as the attribute types, which is wrong for the rare attributes
+/// with case sensitive values: their dns can compare equal when they differ.
+pub fn normalize(dn: &str) -> Result<String> {
+    let rdns = parse(dn)?;
+    Ok(to_string(&rdns).to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_comma() {
+        let rdns = parse(r"cn=Doe\, John,ou=people,dc=example,dc=com").unwrap();
+        assert_eq!(rdns.len(), 4);
+        assert_eq!(rdns[0][0].value, "Doe, John");
+        assert_eq!(rdn(r"cn=Doe\, John,ou=people").unwrap(), r"cn=Doe\, John");
+        assert_eq!(parent(r"cn=Doe\, John,ou=people", 1).unwrap(), "ou=people");
+    }
+
+    #[test]
+    fn hex_escapes() {
+        assert_eq!(parse(r"cn=Jos\C3\A9,dc=x").unwrap()[0][0].value, "José");
+        assert_eq!(parse(r"cn=a\2Cb").unwrap()[0][0].value, "a,b");
+        assert_eq!(
+            normalize(r"CN=Doe\2C John, DC=Example").unwrap(),
+            normalize(r"cn=Doe\, John,dc=example").unwrap()
+        );
+    }
+
+    #[test]
+    fn spaces_and_multi_valued_rdns() {
+        let rdns = parse(r"cn=a+sn=b, ou=x\ ,dc=y").unwrap();
+        assert_eq!(rdns[0].len(), 2);
+        assert_eq!(rdns[0][1].value, "b");
+        assert_eq!(rdns[1][0].value, "x ");
+        assert_eq!(to_string(&rdns), r"cn=a+sn=b,ou=x\ ,dc=y");
+        assert!(parse("cn=a,dc").is_err());
+    }
+}
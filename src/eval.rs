@@ -0,0 +1,464 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client side evaluation of sql expressions against search results.
+//!
+//! Everything that can be expressed as an LDAP filter is sent to the server,
+//! this module handles what can't: the DN_* functions in SELECT, WHERE and
+//! GROUP BY.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Context, Result};
+use ldap3::SearchEntry;
+use sqlparser::ast::{BinaryOperator, Expr, Function, UnaryOperator, Value};
+
+use crate::dn;
+use crate::params;
+
+/// A single search result entry.
+#[derive(Clone, Debug, Default)]
+pub struct Record {
+    pub dn: String,
+    pub attrs: HashMap<String, Vec<String>>,
+    // the aliases of a joined row, in FROM order
+    pub tables: Vec<String>,
+}
+
+impl Record {
+    pub fn new(se: SearchEntry) -> Record {
+        Record {
+            dn: se.dn,
+            attrs: se.attrs,
+            tables: Vec::new(),
+        }
+    }
+
+    /// Returns the values of an attribute, `dn` is the entry's dn.
+    /// The server decides how attribute names are capitalized, so the
+    /// lookup is case insensitive.
+    pub fn get(&self, name: &str) -> Vec<String> {
+        if name.eq_ignore_ascii_case("dn") {
+            return vec![self.dn.clone()];
+        }
+        for (key, values) in &self.attrs {
+            if key.eq_ignore_ascii_case(name) {
+                return values.clone();
+            }
+        }
+        // in a joined row the attributes are qualified with their table
+        // alias, an unqualified name refers to the first table in the FROM
+        // clause that has it
+        if !name.contains('.') {
+            for table in &self.tables {
+                let qualified = format!("{}.{}", table, name);
+                for (key, values) in &self.attrs {
+                    if key.eq_ignore_ascii_case(&qualified) {
+                        return values.clone();
+                    }
+                }
+            }
+        }
+        Vec::new()
+    }
+
+    /// Prefixes all attributes with the table alias (`p.uid`) so the
+    /// record can be merged into a joined row.
+    pub fn qualify(self, alias: &str) -> Record {
+        let mut attrs = HashMap::new();
+        attrs.insert(format!("{}.dn", alias), vec![self.dn.clone()]);
+        for (key, values) in self.attrs {
+            attrs.insert(format!("{}.{}", alias, key), values);
+        }
+        Record {
+            dn: self.dn,
+            attrs,
+            tables: vec![alias.to_string()],
+        }
+    }
+
+    /// Adds the attributes of another (qualified) record to this one.
+    pub fn merge(&mut self, other: &Record) {
+        for (key, values) in &other.attrs {
+            self.attrs.insert(key.to_string(), values.clone());
+        }
+        self.tables.extend(other.tables.iter().cloned());
+    }
+}
+
+/// Returns the string value of a literal, without the sql quotes.
+pub fn literal(value: &Value) -> Option<String> {
+    match value {
+        Value::Number(n) => Some(n.to_string()),
+        Value::SingleQuotedString(s) | Value::NationalStringLiteral(s) => Some(s.to_string()),
+        Value::Boolean(b) => Some(b.to_string().to_uppercase()),
+        Value::Null => None,
+        _ => Some(value.to_string()),
+    }
+}
+
+/// Returns true when the expression has to be evaluated by sqldap because
+/// an LDAP filter cannot express it.
+pub fn is_client_side(expr: &Expr) -> bool {
+    match expr {
+        Expr::Function(_) => true,
+        Expr::BinaryOp { left, right, .. } => is_client_side(left) || is_client_side(right),
+        Expr::UnaryOp { expr, .. } | Expr::Nested(expr) => is_client_side(expr),
+        Expr::IsNull(expr) | Expr::IsNotNull(expr) => is_client_side(expr),
+        // lists that are too long for a single filter, see chunk_filters()
+        Expr::InList { expr, list, .. } => {
+            list.len() > crate::FILTER_BATCH_SIZE
+                || is_client_side(expr)
+                || list.iter().any(is_client_side)
+        }
+        _ => false,
+    }
+}
+
+/// Collects the identifiers an expression refers to, eg: [uid] or
+/// [p, mail]. A bare identifier on the right hand side of a comparison is
+/// a value (`memberuid=username`) and not a reference.
+pub fn references(expr: &Expr, refs: &mut Vec<Vec<String>>) {
+    match expr {
+        Expr::Identifier(ident) if params::is_value(ident) => {}
+        Expr::Identifier(ident) => refs.push(vec![ident.value.clone()]),
+        Expr::CompoundIdentifier(idents) => {
+            refs.push(idents.iter().map(|i| i.value.clone()).collect())
+        }
+        Expr::Function(f) => {
+            for arg in &f.args {
+                references(arg, refs);
+            }
+        }
+        Expr::BinaryOp { left, op, right } => {
+            references(left, refs);
+            let value = match right.as_ref() {
+                Expr::Identifier(_) => op != &BinaryOperator::And && op != &BinaryOperator::Or,
+                _ => false,
+            };
+            if !value {
+                references(right, refs);
+            }
+        }
+        Expr::UnaryOp { expr, .. } | Expr::Nested(expr) => references(expr, refs),
+        Expr::IsNull(expr) | Expr::IsNotNull(expr) => references(expr, refs),
+        Expr::InList { expr, list, .. } => {
+            references(expr, refs);
+            for item in list {
+                references(item, refs);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects the attributes an expression needs from the server for the
+/// table with the given alias: unqualified attributes and the ones
+/// qualified with that alias.
+pub fn attributes(expr: &Expr, alias: &str, attrs: &mut Vec<String>) {
+    let mut refs = Vec::new();
+    references(expr, &mut refs);
+    for r in refs {
+        let attr = match r.as_slice() {
+            [attr] => attr,
+            [table, attr] if table == alias => attr,
+            _ => continue,
+        };
+        if !attr.eq_ignore_ascii_case("dn") && !attrs.contains(attr) {
+            attrs.push(attr.to_string());
+        }
+    }
+}
+
+/// Returns the table aliases an expression refers to, None for an
+/// unqualified reference.
+pub fn qualifiers(expr: &Expr) -> Vec<Option<String>> {
+    let mut refs = Vec::new();
+    references(expr, &mut refs);
+    refs.into_iter()
+        .map(|r| {
+            if r.len() > 1 {
+                Some(r[0].clone())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Rewrites `alias.attr` into `attr`, so a condition on a single table
+/// of a join can be turned into a search filter for that table.
+pub fn unqualify(expr: &Expr, alias: &str) -> Expr {
+    let unq = |e: &Expr| Box::new(unqualify(e, alias));
+    match expr {
+        Expr::CompoundIdentifier(idents) if idents.len() == 2 && idents[0].value == alias => {
+            Expr::Identifier(idents[1].clone())
+        }
+        Expr::BinaryOp { left, op, right } => Expr::BinaryOp {
+            left: unq(left),
+            op: op.clone(),
+            right: unq(right),
+        },
+        Expr::UnaryOp { op, expr } => Expr::UnaryOp {
+            op: op.clone(),
+            expr: unq(expr),
+        },
+        Expr::Nested(expr) => Expr::Nested(unq(expr)),
+        Expr::IsNull(expr) => Expr::IsNull(unq(expr)),
+        Expr::IsNotNull(expr) => Expr::IsNotNull(unq(expr)),
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => Expr::InList {
+            expr: unq(expr),
+            list: list.clone(),
+            negated: *negated,
+        },
+        _ => expr.clone(),
+    }
+}
+
+/// Splits a condition on its top level ANDs.
+pub fn conjuncts(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            let mut result = conjuncts(left);
+            result.append(&mut conjuncts(right));
+            result
+        }
+        Expr::Nested(expr) => conjuncts(expr),
+        _ => vec![expr],
+    }
+}
+
+/// Evaluates an expression to the (possibly multiple) values it has for
+/// the given record.
+pub fn eval(expr: &Expr, record: &Record) -> Result<Vec<String>> {
+    match expr {
+        Expr::Identifier(ident) if params::is_value(ident) => Ok(vec![ident.value.clone()]),
+        Expr::Identifier(ident) => Ok(record.get(&ident.value)),
+        Expr::CompoundIdentifier(idents) => {
+            let name: Vec<String> = idents.iter().map(|i| i.value.clone()).collect();
+            Ok(record.get(&name.join(".")))
+        }
+        Expr::Value(value) => Ok(literal(value).into_iter().collect()),
+        Expr::Nested(expr) => eval(expr, record),
+        Expr::Function(f) => eval_function(f, record),
+        _ => {
+            if matches(expr, record)? {
+                Ok(vec!["TRUE".to_string()])
+            } else {
+                Ok(vec!["FALSE".to_string()])
+            }
+        }
+    }
+}
+
+fn eval_function(f: &Function, record: &Record) -> Result<Vec<String>> {
+    let name = f.name.to_string().to_uppercase();
+    let mut args = Vec::new();
+    for arg in &f.args {
+        args.push(eval(arg, record)?);
+    }
+
+    let single_arg = |n: usize| -> Result<String> {
+        match args.get(n).and_then(|values| values.first()) {
+            Some(value) => Ok(value.to_string()),
+            None => bail!("{}: argument {} is missing or empty", name, n + 1),
+        }
+    };
+
+    // the first argument is usually a dn valued attribute, which can be
+    // multi-valued (eg: member), so the function is applied on every value
+    let first = match args.first() {
+        Some(values) => values.clone(),
+        None => bail!("{} expects a dn as first argument", name),
+    };
+
+    let mut result = Vec::new();
+    match name.as_str() {
+        "DN_PART" => {
+            let attr = single_arg(1)?;
+            for value in first {
+                if let Some(part) = dn::part(&value, &attr)? {
+                    result.push(part);
+                }
+            }
+        }
+        "DN_PARENT" => {
+            let n = if args.len() > 1 {
+                single_arg(1)?
+                    .parse::<usize>()
+                    .context("DN_PARENT: the second argument must be a number")?
+            } else {
+                1
+            };
+            for value in first {
+                result.push(dn::parent(&value, n)?);
+            }
+        }
+        "DN_RDN" => {
+            for value in first {
+                result.push(dn::rdn(&value)?);
+            }
+        }
+        "DN_NORMALIZE" => {
+            for value in first {
+                result.push(dn::normalize(&value)?);
+            }
+        }
+        _ => bail!("Unsupported function {}", name),
+    }
+
+    Ok(result)
+}
+
+/// Evaluates a WHERE clause for the given record.
+pub fn matches(expr: &Expr, record: &Record) -> Result<bool> {
+    match expr {
+        Expr::Nested(expr) => matches(expr, record),
+        Expr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr,
+        } => Ok(!matches(expr, record)?),
+        Expr::IsNull(expr) => Ok(eval(expr, record)?.is_empty()),
+        Expr::IsNotNull(expr) => Ok(!eval(expr, record)?.is_empty()),
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let values = eval(expr, record)?;
+            let mut found = false;
+            for item in list {
+                for value in eval(item, record)? {
+                    if values.iter().any(|v| v.eq_ignore_ascii_case(&value)) {
+                        found = true;
+                    }
+                }
+            }
+            Ok(found != *negated)
+        }
+        Expr::BinaryOp { left, op, right } => match op {
+            BinaryOperator::And => Ok(matches(left, record)? && matches(right, record)?),
+            BinaryOperator::Or => Ok(matches(left, record)? || matches(right, record)?),
+            _ => {
+                let left = eval(left, record)?;
+                // as in the search filter, `uid=jdoe` compares against the
+                // value jdoe and not against an attribute named jdoe
+                let right_values = match right.as_ref() {
+                    Expr::Identifier(ident) => vec![ident.value.clone()],
+                    _ => eval(right, record)?,
+                };
+                // the value of a :parameter has no wildcards
+                let exact = matches!(right.as_ref(), Expr::Identifier(i) if params::is_value(i));
+                // like LDAP, a multi-valued attribute matches when any of
+                // its values does
+                for l in &left {
+                    for r in &right_values {
+                        let hit = match op {
+                            BinaryOperator::Eq if exact => l.to_lowercase() == r.to_lowercase(),
+                            BinaryOperator::NotEq if exact => l.to_lowercase() != r.to_lowercase(),
+                            BinaryOperator::Eq => wildcard_eq(l, r),
+                            BinaryOperator::NotEq => !wildcard_eq(l, r),
+                            BinaryOperator::Gt => compare(l, r) == Ordering::Greater,
+                            BinaryOperator::Lt => compare(l, r) == Ordering::Less,
+                            BinaryOperator::GtEq => compare(l, r) != Ordering::Less,
+                            BinaryOperator::LtEq => compare(l, r) != Ordering::Greater,
+                            _ => bail!("Unsupported operator {}", op),
+                        };
+                        if hit {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(false)
+            }
+        },
+        Expr::Value(Value::Boolean(b)) => Ok(*b),
+        _ => bail!("Unsupported expression in WHERE clause: {}", expr),
+    }
+}
+
+/// Compares numerically when both sides are numbers, otherwise as case
+/// insensitive strings.
+fn compare(left: &str, right: &str) -> Ordering {
+    if let (Ok(l), Ok(r)) = (left.parse::<i64>(), right.parse::<i64>()) {
+        return l.cmp(&r);
+    }
+    left.to_lowercase().cmp(&right.to_lowercase())
+}
+
+/// Case insensitive equality where `*` and `%` in the pattern match any
+/// sequence of characters, like they do in the search filter.
+fn wildcard_eq(value: &str, pattern: &str) -> bool {
+    let value = value.to_lowercase();
+    let pattern = pattern.to_lowercase().replace("%", "*");
+    if !pattern.contains('*') {
+        return value == pattern;
+    }
+
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let mut rest = value.as_str();
+    for (i, part) in parts.iter().enumerate() {
+        if i == 0 {
+            if !rest.starts_with(part) {
+                return false;
+            }
+            rest = &rest[part.len()..];
+        } else if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        } else if let Some(pos) = rest.find(part) {
+            rest = &rest[pos + part.len()..];
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+/// Groups records on the GROUP BY expressions, one row per group.
+pub fn group(
+    columns: &[(String, Expr)],
+    group_by: &[Expr],
+    records: &[Record],
+) -> Result<Vec<Record>> {
+    let mut groups = BTreeSet::new();
+    for record in records {
+        let mut key = Vec::new();
+        for expr in group_by {
+            key.push(eval(expr, record)?.join("\n"));
+        }
+        groups.insert(key);
+    }
+
+    let mut result = Vec::new();
+    for key in groups {
+        let mut row = Record::default();
+        for (name, expr) in columns {
+            let value = match group_by.iter().position(|g| g == expr) {
+                Some(pos) => key[pos].clone(),
+                None => bail!("{} must appear in the GROUP BY clause", expr),
+            };
+            row.attrs.insert(name.clone(), vec![value]);
+        }
+        result.push(row);
+    }
+
+    Ok(result)
+}
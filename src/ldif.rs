@@ -0,0 +1,297 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! LDIF (RFC 2849) change records, eg: for `--dry-run` and `EXPLAIN`.
+//!
+//! The records are what ldapmodify would need to make the same changes.
+//! The backups of `sqldap undo` are read back with parse().
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use ldap3::Mod;
+
+use crate::modify::{Change, Operation, TREE_DELETE_OID};
+
+/// The change record of a change, ending with an empty line.
+pub fn change_record(change: &Change) -> String {
+    let mut ldif = line("dn", &change.dn);
+    match &change.operation {
+        Operation::Modify(mods) => {
+            ldif += "changetype: modify\n";
+            for m in mods {
+                let (kind, attr, values) = match m {
+                    Mod::Add(attr, values) => ("add", attr, sorted(values)),
+                    Mod::Delete(attr, values) => ("delete", attr, sorted(values)),
+                    Mod::Replace(attr, values) => ("replace", attr, sorted(values)),
+                    Mod::Increment(attr, value) => ("increment", attr, vec![value]),
+                };
+                ldif += &format!("{}: {}\n", kind, attr);
+                for value in values {
+                    ldif += &line(attr, value);
+                }
+                ldif += "-\n";
+            }
+        }
+        Operation::Add(attrs) => {
+            ldif += "changetype: add\n";
+            for (attr, values) in attrs {
+                for value in sorted(values) {
+                    ldif += &line(attr, value);
+                }
+            }
+        }
+        Operation::Delete { subtree } => {
+            if *subtree {
+                ldif += &format!("control: {} true\n", TREE_DELETE_OID);
+            }
+            ldif += "changetype: delete\n";
+        }
+        Operation::ModifyDn {
+            rdn,
+            delete_old,
+            new_superior,
+        } => {
+            ldif += "changetype: modrdn\n";
+            ldif += &line("newrdn", rdn);
+            ldif += &format!("deleteoldrdn: {}\n", if *delete_old { 1 } else { 0 });
+            if let Some(new_superior) = new_superior {
+                ldif += &line("newsuperior", new_superior);
+            }
+        }
+        // the password isn't shown, it is set by sqldap with an extended
+        // operation or as unicodePwd
+        Operation::Password(_) => return format!("# {}# set password\n\n", ldif),
+    }
+    ldif + "\n"
+}
+
+/// Parses the change records of an LDIF file. Records without a changetype
+/// (entries) are not supported.
+pub fn parse(text: &str) -> Result<Vec<Change>> {
+    // a line that starts with a space continues the previous line
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (line.strip_prefix(' '), lines.last_mut()) {
+            (Some(rest), Some(last)) if !last.is_empty() => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    let mut changes = Vec::new();
+    let mut record: Vec<(String, String)> = Vec::new();
+    for line in lines.iter().chain(std::iter::once(&String::new())) {
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.trim().is_empty() {
+            if !record.is_empty() {
+                changes.push(parse_record(&record)?);
+                record.clear();
+            }
+            continue;
+        }
+        if line == "-" {
+            record.push((String::from("-"), String::new()));
+            continue;
+        }
+        let pos = line
+            .find(':')
+            .context(format!("Wrong LDIF line: {}", line))?;
+        let attr = line[..pos].to_string();
+        let value = match line[pos + 1..].strip_prefix(':') {
+            Some(encoded) => String::from_utf8(unbase64(encoded.trim())?)
+                .context(format!("Wrong base64 value of {}", attr))?,
+            None => line[pos + 1..].trim_start().to_string(),
+        };
+        if attr != "version" || !record.is_empty() {
+            record.push((attr, value));
+        }
+    }
+    Ok(changes)
+}
+
+fn parse_record(record: &[(String, String)]) -> Result<Change> {
+    let dn = match record.first() {
+        Some((attr, dn)) if attr.eq_ignore_ascii_case("dn") => dn.clone(),
+        _ => bail!("An LDIF record should start with dn:"),
+    };
+    let mut lines = record[1..].iter().peekable();
+    let mut subtree = false;
+    while let Some((attr, value)) = lines.peek() {
+        if !attr.eq_ignore_ascii_case("control") {
+            break;
+        }
+        if value.starts_with(TREE_DELETE_OID) {
+            subtree = true;
+        }
+        lines.next();
+    }
+    let changetype = match lines.next() {
+        Some((attr, value)) if attr.eq_ignore_ascii_case("changetype") => value.to_lowercase(),
+        _ => bail!("The LDIF record of {} has no changetype", dn),
+    };
+    let operation = match changetype.as_str() {
+        "add" => {
+            let mut attrs: Vec<(String, HashSet<String>)> = Vec::new();
+            for (attr, value) in lines {
+                match attrs.iter_mut().find(|(a, _)| a == attr) {
+                    Some((_, values)) => {
+                        values.insert(value.clone());
+                    }
+                    None => attrs.push((attr.clone(), std::iter::once(value.clone()).collect())),
+                }
+            }
+            Operation::Add(attrs)
+        }
+        "delete" => Operation::Delete { subtree },
+        "modrdn" | "moddn" => {
+            let mut rdn = None;
+            let mut delete_old = true;
+            let mut new_superior = None;
+            for (attr, value) in lines {
+                match attr.to_lowercase().as_str() {
+                    "newrdn" => rdn = Some(value.clone()),
+                    "deleteoldrdn" => delete_old = value.trim() == "1",
+                    "newsuperior" => new_superior = Some(value.clone()),
+                    _ => bail!("Unknown {} in the modrdn record of {}", attr, dn),
+                }
+            }
+            Operation::ModifyDn {
+                rdn: rdn.context(format!("The modrdn record of {} has no newrdn", dn))?,
+                delete_old,
+                new_superior,
+            }
+        }
+        "modify" => {
+            let mut mods = Vec::new();
+            while let Some((kind, attr)) = lines.next() {
+                let mut values = HashSet::new();
+                for (a, value) in lines.by_ref() {
+                    if a == "-" {
+                        break;
+                    }
+                    values.insert(value.clone());
+                }
+                mods.push(match kind.to_lowercase().as_str() {
+                    "add" => Mod::Add(attr.clone(), values),
+                    "delete" => Mod::Delete(attr.clone(), values),
+                    "replace" => Mod::Replace(attr.clone(), values),
+                    "increment" => match values.into_iter().next() {
+                        Some(value) => Mod::Increment(attr.clone(), value),
+                        None => bail!("increment: {} of {} has no value", attr, dn),
+                    },
+                    _ => bail!("Unknown {}: {} in the modify record of {}", kind, attr, dn),
+                });
+            }
+            Operation::Modify(mods)
+        }
+        _ => bail!("Unsupported changetype {} of {}", changetype, dn),
+    };
+    Ok(Change { dn, operation })
+}
+
+// The values of a set in a fixed order, so the same change gives the same
+// record.
+fn sorted(values: &HashSet<String>) -> Vec<&String> {
+    let mut values: Vec<&String> = values.iter().collect();
+    values.sort();
+    values
+}
+
+// `attr: value`, or `attr:: base64` for values that can't be written as
+// they are.
+fn line(attr: &str, value: &str) -> String {
+    let safe = !value.starts_with([' ', ':', '<'])
+        && !value.ends_with(' ')
+        && value
+            .chars()
+            .all(|c| c.is_ascii() && c != '\0' && c != '\n' && c != '\r');
+    if safe {
+        format!("{}: {}\n", attr, value)
+    } else {
+        format!("{}:: {}\n", attr, base64(value.as_bytes()))
+    }
+}
+
+const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn unbase64(encoded: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut n: u32 = 0;
+    let mut bits = 0;
+    for c in encoded.bytes().filter(|c| *c != b'=') {
+        let value = match ALPHABET.iter().position(|a| *a == c) {
+            Some(value) => value as u32,
+            None => bail!("Wrong base64 value {}", encoded),
+        };
+        n = n << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((n >> bits & 0xff) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trip() {
+        assert_eq!(base64(b"sqldap"), "c3FsZGFw");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+        for text in ["", "a", "ab", "abc", "José ", " \0:<"] {
+            assert_eq!(unbase64(&base64(text.as_bytes())).unwrap(), text.as_bytes());
+        }
+        assert!(unbase64("a*b").is_err());
+    }
+
+    #[test]
+    fn change_record_round_trip() {
+        let mut values = HashSet::new();
+        values.insert(String::from("José"));
+        values.insert(String::from(" leading space"));
+        let change = Change {
+            dn: String::from("uid=jdoe,ou=people,dc=example,dc=com"),
+            operation: Operation::Modify(vec![Mod::Replace(String::from("cn"), values)]),
+        };
+        let record = change_record(&change);
+        assert!(record.contains("cn:: IGxlYWRpbmcgc3BhY2U=\n"));
+        let parsed = parse(&format!("version: 1\n\n{}", record)).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(change_record(&parsed[0]), record);
+    }
+}
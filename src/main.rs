@@ -12,23 +12,46 @@
 
 #![warn(clippy::all)]
 
+mod account;
+mod backup;
+mod dn;
+mod eval;
+mod ldif;
+mod modify;
+mod params;
+mod select;
+mod server;
+mod transaction;
+mod view;
+
 //use std::fs;
 
 use simple_logger::SimpleLogger;
+use sqlparser::ast::BinaryOperator::{And, Or};
 use sqlparser::ast::Expr;
 use sqlparser::ast::Ident;
+use sqlparser::ast::JoinConstraint;
+use sqlparser::ast::JoinOperator;
+use sqlparser::ast::ObjectName;
+use sqlparser::ast::Query;
+use sqlparser::ast::Select;
 use sqlparser::ast::SelectItem;
 use sqlparser::ast::SetExpr;
+use sqlparser::ast::SetOperator;
+use sqlparser::ast::SetVariableValue;
 use sqlparser::ast::Statement;
 use sqlparser::ast::TableFactor;
+use sqlparser::ast::Value;
 use sqlparser::dialect::*;
-use sqlparser::parser::Parser;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer, Whitespace};
 //use sqlparser::ast::Expr::BinaryOp;
 
 //use derive_more::Into;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::Write;
 
 use term_table::{
     row::Row,
@@ -41,16 +64,44 @@ use ini::Ini;
 use anyhow::{bail, Context, Result};
 
 use ldap3::Mod::Replace;
-use ldap3::{LdapConn, Mod, Scope, SearchEntry, LdapConnSettings};
+use ldap3::{DerefAliases, Scope};
+
+use eval::Record;
+use params::Parameters;
+use select::Referrals;
+use server::{Output, Servers};
+use transaction::Transaction;
+
+// maximum number of values in one (|(attr=a)(attr=b)...) search filter,
+// longer lists are spread over multiple searches
+const FILTER_BATCH_SIZE: usize = 100;
 
 const USAGE: &str = r#"
 Usage:
 $ sqldap filename.sql [server]
 or
 $ sqldap "SELECT uid FROM dc=example,dc=com WHERE gid=100" [server]
+or
+$ sqldap @savedquery [server] [name=value ...] [value ...]
+or
+$ sqldap undo sqldap-server-20240131-235959.ldif [server]
+
+Options:
+  --dry-run  show the changes of INSERT, UPDATE, DELETE and ALTER ENTRY
+             as LDIF, without making them
+  --yes      don't ask for confirmation when a statement changes more
+             entries than confirm= of the server allows (default 1)
+  --force    allow UPDATE and DELETE without WHERE
+
+Before entries are changed their old values are saved in an LDIF file,
+undo undoes the changes of that file.
 
 When server is not provided then the first found server in sqldap.ini
-will be used as server.
+will be used as server. Use '*' or ALL as server to run the queries
+on all servers.
+
+Values for :name placeholders in the query are given as name=value,
+or without a name in the order the placeholders appear.
 "#;
 
 const CONFIG_FILE_EXAMPLE: &str = r#"
@@ -81,7 +132,9 @@ fn parse_selection(selection: Expr) -> Result<String> {
             }
             match *left {
                 Expr::Identifier(ident) => result_string = result_string + "(" + &ident.to_string(),
-                Expr::Value(value) => result_string = result_string + "(" + &value.to_string(),
+                Expr::Value(value) => {
+                    result_string = result_string + "(" + &eval::literal(&value).unwrap_or_default()
+                }
                 Expr::Wildcard => {
                     result_string += "(*";
                 }
@@ -97,6 +150,10 @@ fn parse_selection(selection: Expr) -> Result<String> {
                 result_string += &op.to_string();
             }
             match *right {
+                // the value of a :parameter, see params.rs
+                Expr::Identifier(ident) if params::is_value(&ident) => {
+                    result_string = result_string + &escape_filter_value(&ident.value) + ")";
+                }
                 Expr::Identifier(ident) => {
                     let val = ident
                         .to_string()
@@ -110,7 +167,8 @@ fn parse_selection(selection: Expr) -> Result<String> {
                     //let val = value.to_string().trim_end_matches("\"").trim_start_matches("\"").to_string();
                     //let val = val.replace("%","*");
                     //result_string = result_string + &val.to_string() + ")";
-                    result_string = result_string + &value.to_string() + ")";
+                    result_string =
+                        result_string + &eval::literal(&value).unwrap_or_default() + ")";
                 }
                 Expr::Wildcard => {
                     result_string += "*)";
@@ -125,280 +183,1427 @@ fn parse_selection(selection: Expr) -> Result<String> {
             result_string += &parse_selection(*expression).context("Cannot parse selection")?;
             result_string += ")";
         }
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let attr = match *expr {
+                Expr::Identifier(ident) => ident.value,
+                _ => bail!("Non supported variant"),
+            };
+            let mut filter = String::from("");
+            for item in list {
+                let value = match item {
+                    Expr::Value(value) => eval::literal(&value).unwrap_or_default(),
+                    Expr::Identifier(ident) => ident.value,
+                    _ => bail!("Non supported variant"),
+                };
+                filter += &format!("({}={})", attr, escape_filter_value(&value));
+            }
+            // an empty list (eg: the subquery found nothing) matches nothing
+            filter = if filter.is_empty() {
+                String::from("(!(objectClass=*))")
+            } else {
+                format!("(|{})", filter)
+            };
+            if negated {
+                filter = format!("(!{})", filter);
+            }
+            result_string += &filter;
+        }
         _ => bail!("Non supported variant"),
     }
 
     Ok(result_string)
 }
 
-// TODO: UPDATE
-// see: https://docs.rs/ldap3/0.8.1/ldap3/struct.Ldap.html#method.modifydn
-// or: https://docs.rs/ldap3/0.8.1/ldap3/struct.Ldap.html#method.modify
+// Translates the parts of a WHERE clause an LDAP filter can express.
+// Conditions that sqldap has to evaluate itself (eg: DN_PART(dn, 'ou')='x')
+// are left out, which only widens the search as long as they are not
+// below an OR. Returns None when nothing could be translated.
+fn ldap_filter(selection: &Expr) -> Result<Option<String>> {
+    if !eval::is_client_side(selection) {
+        let mut result = parse_selection(selection.clone()).context("Cannot parse selection")?;
+        if !result.starts_with('(') {
+            result = format!("({})", result);
+        }
+        return Ok(Some(result));
+    }
+
+    match selection {
+        Expr::Nested(expression) => ldap_filter(expression),
+        Expr::BinaryOp { left, op, right } if op == &And || op == &Or => {
+            let left = ldap_filter(left)?;
+            let right = ldap_filter(right)?;
+            match (op.to_string().as_str(), left, right) {
+                ("AND", Some(l), Some(r)) => Ok(Some(format!("(&{}{})", l, r))),
+                ("AND", Some(l), None) => Ok(Some(l)),
+                ("AND", None, Some(r)) => Ok(Some(r)),
+                ("OR", Some(l), Some(r)) => Ok(Some(format!("(|{}{})", l, r))),
+                _ => Ok(None),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+// Replaces `attr IN (SELECT ...)` by the list of values the subquery
+// returns, so it can become part of the search filter.
+fn resolve_subqueries(expr: Expr, servers: &mut Servers, ctes: &Ctes) -> Result<Expr> {
+    let mut resolve = |e: Box<Expr>| -> Result<Box<Expr>> {
+        Ok(Box::new(resolve_subqueries(*e, servers, ctes)?))
+    };
+    Ok(match expr {
+        Expr::InSubquery {
+            expr,
+            subquery,
+            negated,
+        } => {
+            let subquery = parse_with(*subquery, servers, ctes)?;
+            if !single_column(&subquery) {
+                bail!("A subquery used with IN must select exactly one column");
+            }
+            let mut values = Vec::new();
+            let records = select::run(servers, &subquery)
+                .and_then(|found| found.complete())
+                .context("Subquery failed")?;
+            for record in records {
+                for value in record.attrs.values().flatten() {
+                    if !values.contains(value) {
+                        values.push(value.to_string());
+                    }
+                }
+            }
+            Expr::InList {
+                expr,
+                list: values
+                    .into_iter()
+                    .map(|v| Expr::Value(Value::SingleQuotedString(v)))
+                    .collect(),
+                negated,
+            }
+        }
+        Expr::BinaryOp { left, op, right } => Expr::BinaryOp {
+            left: resolve(left)?,
+            op,
+            right: resolve(right)?,
+        },
+        Expr::UnaryOp { op, expr } => Expr::UnaryOp {
+            op,
+            expr: resolve(expr)?,
+        },
+        Expr::Nested(expr) => Expr::Nested(resolve(expr)?),
+        _ => expr,
+    })
+}
+
+// Whether a query selects one column, for both sides of a UNION.
+fn single_column(query: &SqldapQuery) -> bool {
+    match &query.set_operation {
+        Some(set) => single_column(&set.left) && single_column(&set.right),
+        None => !query.wildcard && query.columns.len() == 1,
+    }
+}
+
+// Whether an expression has an IN list that is too long for one filter.
+fn long_list(expr: &Expr) -> bool {
+    match expr {
+        Expr::InList { list, .. } => list.len() > FILTER_BATCH_SIZE,
+        Expr::BinaryOp { left, right, .. } => long_list(left) || long_list(right),
+        Expr::UnaryOp { expr, .. } | Expr::Nested(expr) => long_list(expr),
+        _ => false,
+    }
+}
+
+// A filter with thousands of values can exceed what the server accepts.
+// When the WHERE clause is `... AND attr IN (<long list>)`, the list is
+// split and every part gets its own search filter.
+fn chunk_filters(selection: &Expr) -> Result<Vec<String>> {
+    let conditions = eval::conjuncts(selection);
+    let pos = conditions.iter().position(|c| match c {
+        Expr::InList {
+            expr,
+            list,
+            negated: false,
+        } => list.len() > FILTER_BATCH_SIZE && !eval::is_client_side(expr),
+        _ => false,
+    });
+    let pos = match pos {
+        Some(pos) => pos,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut filters = Vec::new();
+    if let Expr::InList { expr, list, .. } = conditions[pos] {
+        for chunk in list.chunks(FILTER_BATCH_SIZE) {
+            let mut chunked = conditions.clone();
+            let in_list = Expr::InList {
+                expr: expr.clone(),
+                list: chunk.to_vec(),
+                negated: false,
+            };
+            chunked[pos] = &in_list;
+            let mut condition = chunked[0].clone();
+            for c in &chunked[1..] {
+                condition = Expr::BinaryOp {
+                    left: Box::new(condition),
+                    op: And,
+                    right: Box::new((*c).clone()),
+                };
+            }
+            if let Some(filter) = ldap_filter(&condition)? {
+                filters.push(filter);
+            }
+        }
+    }
+    Ok(filters)
+}
+
+// Escapes a value for use in a search filter (RFC 4515).
+fn escape_filter_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '*' => escaped.push_str("\\2a"),
+            '(' => escaped.push_str("\\28"),
+            ')' => escaped.push_str("\\29"),
+            '\\' => escaped.push_str("\\5c"),
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// the variants are spelled like the sql keywords of the statements
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, PartialEq)]
 enum QueryType {
     SELECT = 0,
     SHOW = 1,
     UPDATE = 2,
+    SET = 3,
+    INSERT = 4,
+    DELETE = 5,
+    // BEGIN, COMMIT or ROLLBACK
+    TRANSACTION = 6,
+}
+
+impl QueryType {
+    // statements that change the directory
+    fn writes(&self) -> bool {
+        *self == QueryType::UPDATE || *self == QueryType::INSERT || *self == QueryType::DELETE
+    }
 }
 
 struct SqldapQuery {
     qtype: QueryType,
     identifiers: Vec<String>,
+    // the SELECT list: column header and the expression to evaluate
+    columns: Vec<(String, Expr)>,
     wildcard: bool,
+    // the section in sqldap.ini of the server that is searched
+    server: String,
     table: String,
+    alias: String,
+    scope: Scope,
+    deref: DerefAliases,
+    referrals: Referrals,
     filter: String,
+    // a filter per search when a long IN list is split, see chunk_filters()
+    chunks: Vec<String>,
+    joins: Vec<JoinedTable>,
+    // UNION, INTERSECT or EXCEPT of two queries
+    set_operation: Option<SetOperation>,
+    // the table is a WITH query, these are its results
+    rows: Option<select::Found>,
+    // WHERE clause that has to be checked by sqldap, see ldap_filter()
+    selection: Option<Expr>,
+    group_by: Vec<Expr>,
     var: String,
+    // SET var = value
+    value: String,
+    // the entries an UPDATE changes, they are modified after parsing
+    changes: Vec<modify::Change>,
+    // EXPLAIN UPDATE ...: the changes are shown instead of made
+    explain: bool,
+    // UPDATE or DELETE without WHERE, which changes the whole table
+    unfiltered: bool,
+}
+
+// A table in the FROM clause after the first one: JOIN @people p ON ...
+struct JoinedTable {
+    alias: String,
+    server: String,
+    table: String,
+    scope: Scope,
+    deref: DerefAliases,
+    referrals: Referrals,
+    filter: String,
+    identifiers: Vec<String>,
+    on: Expr,
+    outer: bool,
+}
+
+struct SetOperation {
+    op: SetOperator,
+    all: bool,
+    left: Box<SqldapQuery>,
+    right: Box<SqldapQuery>,
+}
+
+impl SqldapQuery {
+    fn new() -> SqldapQuery {
+        SqldapQuery {
+            identifiers: Vec::new(),
+            columns: Vec::new(),
+            wildcard: false,
+            server: String::from(""),
+            table: String::from(""),
+            alias: String::from(""),
+            scope: Scope::Subtree,
+            deref: DerefAliases::Never,
+            referrals: Referrals::Report,
+            filter: String::from(""),
+            chunks: Vec::new(),
+            joins: Vec::new(),
+            set_operation: None,
+            rows: None,
+            selection: None,
+            group_by: Vec::new(),
+            qtype: QueryType::SELECT,
+            var: String::from(""),
+            value: String::from(""),
+            changes: Vec::new(),
+            explain: false,
+            unfiltered: false,
+        }
+    }
+}
+
+// The results of the common table expressions (WITH name AS (SELECT ...))
+// of the statement that is being parsed, by name.
+type Ctes = HashMap<String, select::Found>;
+
+// Runs the common table expressions of a query, each of them once, and
+// parses the query itself with their results available as tables.
+fn parse_with(q: Query, servers: &mut Servers, ctes: &Ctes) -> Result<SqldapQuery> {
+    if q.ctes.is_empty() {
+        return parse_set_expr(q.body, servers, ctes);
+    }
+
+    let mut ctes = ctes.clone();
+    for cte in q.ctes {
+        let name = cte.alias.name.value;
+        let query = parse_with(cte.query, servers, &ctes)?;
+        let rows = select::run(servers, &query).context(format!("WITH {} failed", name))?;
+        ctes.insert(name.to_lowercase(), rows);
+    }
+    parse_set_expr(q.body, servers, &ctes)
+}
+
+// Parses the body of a query, which is either a single SELECT or a set
+// operation (UNION, INTERSECT, EXCEPT) between two of them.
+fn parse_set_expr(body: SetExpr, servers: &mut Servers, ctes: &Ctes) -> Result<SqldapQuery> {
+    match body {
+        SetExpr::Select(s) => parse_select(*s, servers, ctes),
+        SetExpr::Query(q) => parse_with(*q, servers, ctes),
+        SetExpr::SetOperation {
+            op,
+            all,
+            left,
+            right,
+        } => {
+            let left = parse_set_expr(*left, servers, ctes)?;
+            let right = parse_set_expr(*right, servers, ctes)?;
+
+            // the columns of both sides are aligned by name
+            let mut query = SqldapQuery::new();
+            query.wildcard = left.wildcard || right.wildcard;
+            for (name, _) in left.columns.iter().chain(right.columns.iter()) {
+                if !query
+                    .columns
+                    .iter()
+                    .any(|(n, _)| n.eq_ignore_ascii_case(name))
+                {
+                    query
+                        .columns
+                        .push((name.clone(), Expr::Identifier(Ident::new(name))));
+                }
+            }
+            query.set_operation = Some(SetOperation {
+                op,
+                all,
+                left: Box::new(left),
+                right: Box::new(right),
+            });
+            Ok(query)
+        }
+        _ => bail!("Unsupported Statement::Query(s)"),
+    }
+}
+
+// `@table` doesn't exist in the [server.tables] section of the server,
+// a query on all servers skips these servers.
+#[derive(Debug)]
+struct TableNotConfigured {
+    table: String,
+    server: String,
+}
+
+impl std::fmt::Display for TableNotConfigured {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Table {} is not configured in section {}.tables",
+            self.table, self.server
+        )
+    }
+}
+
+impl std::error::Error for TableNotConfigured {}
+
+// A table in the FROM clause.
+struct TableRef {
+    // the section in sqldap.ini of the server that is searched
+    server: String,
+    // the search base
+    table: String,
+    alias: String,
+    scope: Scope,
+    deref: DerefAliases,
+    referrals: Referrals,
+    // the table is a @table of the .tables section, not a dn
+    configured: bool,
+}
+
+// BASE, ONE or SUB, as used in table hints and in the .tables section.
+fn parse_scope(scope: &str) -> Result<Scope> {
+    match scope.to_uppercase().as_str() {
+        "BASE" => Ok(Scope::Base),
+        "ONE" | "ONELEVEL" => Ok(Scope::OneLevel),
+        "SUB" | "SUBTREE" => Ok(Scope::Subtree),
+        _ => bail!("Unknown scope {}, use BASE, ONE or SUB", scope),
+    }
+}
+
+// When the server dereferences alias entries: NEVER, SEARCH, FIND or ALWAYS.
+fn parse_deref(deref: &str) -> Result<DerefAliases> {
+    match deref.to_uppercase().as_str() {
+        "NEVER" => Ok(DerefAliases::Never),
+        "SEARCH" | "SEARCHING" => Ok(DerefAliases::Searching),
+        "FIND" | "FINDING" => Ok(DerefAliases::Finding),
+        "ALWAYS" => Ok(DerefAliases::Always),
+        _ => bail!("Unknown deref {}, use NEVER, SEARCH, FIND or ALWAYS", deref),
+    }
+}
+
+// IGNORE, REPORT or FOLLOW, see select::Referrals.
+fn parse_referrals(referrals: &str) -> Result<Referrals> {
+    match referrals.to_uppercase().as_str() {
+        "IGNORE" => Ok(Referrals::Ignore),
+        "REPORT" => Ok(Referrals::Report),
+        "FOLLOW" => Ok(Referrals::Follow),
+        _ => bail!(
+            "Unknown referrals {}, use IGNORE, REPORT or FOLLOW",
+            referrals
+        ),
+    }
+}
+
+// `examplead.people` is the table people of the server examplead, other
+// tables belong to the default server. The search scope is taken from,
+// in this order: a hint (`@people WITH (SCOPE=ONE)`), a table function
+// (`ONELEVEL('ou=people,dc=example,dc=com')`), `people.scope=one` in the
+// .tables section of the server, or it is a subtree search.
+// DEREF and REFERRALS hints override the deref and referrals of the server.
+fn parse_table(relation: TableFactor, servers: &Servers) -> Result<TableRef> {
+    match relation {
+        TableFactor::Table {
+            name,
+            alias,
+            args,
+            with_hints,
+        } => {
+            let mut parts = name.0;
+            let qualified = parts.len() == 2 && servers.get(&parts[0].value).is_some();
+            let servername = if qualified {
+                parts.remove(0).value
+            } else {
+                servers.default.clone()
+            };
+
+            let mut scope = None;
+            let mut table = if args.is_empty() {
+                if qualified {
+                    format!("@{}", parts[0].value.trim_start_matches('@'))
+                } else {
+                    ObjectName(parts.clone())
+                        .to_string()
+                        .trim_end_matches('\"')
+                        .trim_start_matches('\"')
+                        .to_string()
+                }
+            } else {
+                let function = ObjectName(parts.clone()).to_string();
+                scope = match parse_scope(&function) {
+                    Ok(scope) if args.len() == 1 => Some(scope),
+                    _ => bail!(
+                        "Unsupported table function {}, use BASE(dn), ONELEVEL(dn) or SUBTREE(dn)",
+                        function
+                    ),
+                };
+                match &args[0] {
+                    Expr::Value(value) => eval::literal(value).unwrap_or_default(),
+                    Expr::Identifier(ident) => ident.value.clone(),
+                    _ => bail!("The argument of {}() should be a dn or a table", function),
+                }
+            };
+            let alias = match alias {
+                Some(alias) => alias.name.value,
+                None if !args.is_empty() && !table.starts_with('@') => {
+                    parts[0].value.to_lowercase()
+                }
+                None => table.trim_start_matches('@').to_string(),
+            };
+
+            let mut deref = None;
+            let mut referrals = None;
+            for hint in with_hints {
+                let (key, value) = match &hint {
+                    Expr::BinaryOp { left, op, right } if op.to_string() == "=" => {
+                        match (left.as_ref(), right.as_ref()) {
+                            (Expr::Identifier(key), Expr::Identifier(value)) => {
+                                (key.value.to_uppercase(), value.value.clone())
+                            }
+                            (Expr::Identifier(key), Expr::Value(value)) => (
+                                key.value.to_uppercase(),
+                                eval::literal(value).unwrap_or_default(),
+                            ),
+                            _ => bail!("Unsupported table hint {}", hint),
+                        }
+                    }
+                    _ => bail!("Unsupported table hint {}", hint),
+                };
+                match key.as_str() {
+                    "SCOPE" => scope = Some(parse_scope(&value)?),
+                    "DEREF" => deref = Some(parse_deref(&value)?),
+                    "REFERRALS" => referrals = Some(parse_referrals(&value)?),
+                    _ => bail!("Unsupported table hint {}", hint),
+                }
+            }
+
+            let configured = table.starts_with('@');
+            if let Some(server) = servers.get(&servername) {
+                if deref.is_none() {
+                    if let Some(default) = &server.deref {
+                        deref = Some(
+                            parse_deref(default)
+                                .context(format!("Wrong deref in section {}", servername))?,
+                        );
+                    }
+                }
+                if referrals.is_none() {
+                    if let Some(default) = &server.referrals {
+                        referrals = Some(
+                            parse_referrals(default)
+                                .context(format!("Wrong referrals in section {}", servername))?,
+                        );
+                    }
+                }
+                if scope.is_none() {
+                    if let Some(default) = server.scopes.get(table.trim_start_matches('@')) {
+                        scope = Some(parse_scope(default).context(format!(
+                            "Wrong scope for table {} in section {}.tables",
+                            table, servername
+                        ))?);
+                    }
+                }
+                for (t, value) in &server.tables {
+                    table = table.replace(&format!("@{}", t), value);
+                }
+            }
+            if table.starts_with('@') {
+                bail!(TableNotConfigured {
+                    table: table.trim_start_matches('@').to_string(),
+                    server: servername,
+                });
+            }
+            Ok(TableRef {
+                server: servername,
+                table,
+                alias,
+                scope: scope.unwrap_or(Scope::Subtree),
+                deref: deref.unwrap_or(DerefAliases::Never),
+                referrals: referrals.unwrap_or(Referrals::Report),
+                configured,
+            })
+        }
+        _ => bail!("Unsupported TableFactor"),
+    }
+}
+
+// The table of UPDATE @people or INSERT INTO @people, it is resolved like
+// the table of SELECT ... FROM @people.
+fn parse_write_table(name: ObjectName, servers: &Servers) -> Result<TableRef> {
+    let relation = TableFactor::Table {
+        name,
+        alias: None,
+        args: Vec::new(),
+        with_hints: Vec::new(),
+    };
+    let mut table = parse_table(relation, servers)?;
+    if !table.configured {
+        table.table = with_base(table.table, &servers.session.base);
+    }
+    Ok(table)
+}
+
+// The search filter of the WHERE clause of UPDATE or DELETE. Unlike
+// ldap_filter() it fails on conditions the filter can't express, leaving
+// them out would change more entries than the WHERE clause matches.
+fn write_filter(selection: Option<Expr>) -> Result<String> {
+    Ok(match selection {
+        // the entries to change are found with one search, a SELECT checks
+        // such a list itself instead
+        Some(selection) if long_list(&selection) => bail!(
+            "An IN list of more than {} values can't select the entries to change, split the statement",
+            FILTER_BATCH_SIZE
+        ),
+        Some(selection) => {
+            let mut result = parse_selection(selection).context("Cannot parse selection")?;
+            if !result.starts_with('(') {
+                result = format!("({})", result);
+            }
+            result
+        }
+        // only with --force, see main()
+        None => String::from("(objectClass=*)"),
+    })
+}
+
+// The rest of the statement when it starts with keyword, eg: EXPLAIN.
+fn strip_keyword(dialect: &dyn Dialect, sql: &str, keyword: &str) -> Option<String> {
+    let tokens = Tokenizer::new(dialect, sql).tokenize().ok()?;
+    let first = tokens.iter().find(|t| !matches!(t, Token::Whitespace(_)))?;
+    if !matches!(first, Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(keyword))
+    {
+        return None;
+    }
+    // the rest of the statement as it was written, printing its tokens
+    // would lose the escaping of quotes in strings
+    let sql = sql.trim_start();
+    match sql.get(..keyword.len()) {
+        Some(word) if word.eq_ignore_ascii_case(keyword) => Some(sql[keyword.len()..].to_string()),
+        _ => None,
+    }
 }
 
-fn parse_query(
-    dialect: Box<dyn Dialect>,
+// The tokens of sql with the values of its :parameters.
+fn tokenize(dialect: &dyn Dialect, sql: &str, servers: &Servers) -> Result<Vec<Token>> {
+    let tokens = Tokenizer::new(dialect, sql)
+        .tokenize()
+        .map_err(ParserError::from)
+        .context("Cannot parse sql")?;
+    params::substitute(tokens, &servers.session.parameters)
+}
+
+// The value of a quoted string or of a :parameter.
+fn string(token: &Token) -> Option<&str> {
+    match token {
+        Token::SingleQuotedString(s) => Some(s),
+        Token::Word(w) if w.quote_style == Some('\'') => Some(&w.value),
+        _ => None,
+    }
+}
+
+// The statements of a script. A ; in a string or a comment doesn't end a
+// statement, only the ones the tokenizer reads as a separator do.
+fn statements(sql: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    for (pos, _) in sql.match_indices(';') {
+        let separator = match Tokenizer::new(&GenericDialect {}, &sql[start..=pos]).tokenize() {
+            Ok(tokens) => tokens.last() == Some(&Token::SemiColon),
+            // eg: the ; is in a string that ends further on
+            Err(_) => false,
+        };
+        if separator {
+            statements.push(sql[start..pos].trim());
+            start = pos + 1;
+        }
+    }
+    statements.push(sql[start..].trim());
+    statements
+}
+
+const ALTER_ENTRY_USAGE: &str =
+    "Use ALTER ENTRY 'dn' MOVE TO 'dn of the new parent' or ALTER ENTRY @table WHERE ... MOVE TO '...'";
+
+// ALTER ENTRY 'uid=jdoe,ou=people,...' MOVE TO 'ou=former,...' or ALTER
+// ENTRY @people WHERE uid=jdoe MOVE TO '...'. sqlparser doesn't know this
+// statement, so it is recognized on its tokens. Returns None for all other
+// statements.
+fn parse_alter_entry(
+    dialect: &dyn Dialect,
+    sql: &str,
+    servers: &mut Servers,
+) -> Result<Option<SqldapQuery>> {
+    let tokens = match tokenize(dialect, sql, servers) {
+        Ok(tokens) => tokens,
+        // the parser reports the error
+        Err(_) => return Ok(None),
+    };
+    let words: Vec<(usize, &Token)> = tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| !matches!(t, Token::Whitespace(_) | Token::SemiColon))
+        .collect();
+    let is = |token: &Token, keyword: &str| matches!(token, Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(keyword));
+    if words.len() < 2 || !is(words[0].1, "ALTER") || !is(words[1].1, "ENTRY") {
+        return Ok(None);
+    }
+    let (target, new_superior) = match &words[2..] {
+        [target @ .., (_, to_move), (_, to), (_, dn)]
+            if is(to_move, "MOVE") && is(to, "TO") && !target.is_empty() =>
+        {
+            match string(dn) {
+                Some(dn) => (target, with_base(dn.to_string(), &servers.session.base)),
+                None => bail!(ALTER_ENTRY_USAGE),
+            }
+        }
+        _ => bail!(ALTER_ENTRY_USAGE),
+    };
+
+    let mut query = SqldapQuery::new();
+    query.qtype = QueryType::UPDATE;
+    let mut dns = target_entries(&tokens, target, servers, &mut query, ALTER_ENTRY_USAGE)?;
+    // entries below another entry that moves, move along with it
+    let normalize = |dn: &str| dn::normalize(dn).unwrap_or_else(|_| dn.to_lowercase());
+    let parents: Vec<String> = dns.iter().map(|dn| format!(",{}", normalize(dn))).collect();
+    dns.retain(|dn| !parents.iter().any(|p| normalize(dn).ends_with(p.as_str())));
+    for dn in dns {
+        query.changes.push(modify::Change {
+            operation: modify::move_to(&dn, &new_superior)?,
+            dn,
+        });
+    }
+    Ok(Some(query))
+}
+
+const SET_PASSWORD_USAGE: &str =
+    "Use SET PASSWORD FOR 'dn' = 'new password' or UPDATE @table SET userPassword = PASSWORD('...') WHERE ...";
+
+// SET PASSWORD FOR 'uid=jdoe,ou=people,...' = 'secret', the password can
+// also be given as PASSWORD('secret'). Like ALTER ENTRY it is recognized
+// on its tokens, returns None for all other statements.
+fn parse_set_password(
+    dialect: &dyn Dialect,
     sql: &str,
-    tables: HashMap<String, String>,
-    ldap: &mut LdapConn,
-) -> Result<SqldapQuery> {
-    let parse_result = Parser::parse_sql(&*dialect, &sql);
-    let parse_result = parse_result.context("Cannot parse sql")?;
-
-    let mut query: SqldapQuery = SqldapQuery {
-        identifiers: Vec::new(),
-        wildcard: false,
-        table: String::from(""),
-        filter: String::from(""),
-        qtype: QueryType::SELECT,
-        var: String::from(""),
+    servers: &mut Servers,
+) -> Result<Option<SqldapQuery>> {
+    let tokens = match tokenize(dialect, sql, servers) {
+        Ok(tokens) => tokens,
+        Err(_) => return Ok(None),
+    };
+    let words: Vec<&Token> = tokens
+        .iter()
+        .filter(|t| !matches!(t, Token::Whitespace(_) | Token::SemiColon))
+        .collect();
+    let is = |token: &Token, keyword: &str| matches!(token, Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(keyword));
+    if words.len() < 2 || !is(words[0], "SET") || !is(words[1], "PASSWORD") {
+        return Ok(None);
+    }
+    let (dn, password) = match &words[2..] {
+        [for_, dn, Token::Eq, password] if is(for_, "FOR") => (string(dn), string(password)),
+        [for_, dn, Token::Eq, function, Token::LParen, password, Token::RParen]
+            if is(for_, "FOR") && is(function, "PASSWORD") =>
+        {
+            (string(dn), string(password))
+        }
+        _ => bail!(SET_PASSWORD_USAGE),
+    };
+    let (dn, password) = match (dn, password) {
+        (Some(dn), Some(password)) => (dn, password),
+        _ => bail!(SET_PASSWORD_USAGE),
     };
+    let mut query = SqldapQuery::new();
+    query.qtype = QueryType::UPDATE;
+    query.server = servers.default.clone();
+    query.changes.push(modify::Change {
+        dn: with_base(dn.to_string(), &servers.session.base),
+        operation: modify::Operation::Password(password.to_string()),
+    });
+    Ok(Some(query))
+}
+
+const ACCOUNT_USAGE: &str =
+    "Use UNLOCK ACCOUNT 'dn' or UNLOCK ACCOUNT @table WHERE ..., or LOCK ACCOUNT ...";
+
+// LOCK ACCOUNT 'uid=jdoe,ou=people,...' or UNLOCK ACCOUNT @people WHERE
+// passwordretrycount>=3, see account.rs. Returns None for all other
+// statements.
+fn parse_account(
+    dialect: &dyn Dialect,
+    sql: &str,
+    servers: &mut Servers,
+) -> Result<Option<SqldapQuery>> {
+    let tokens = match tokenize(dialect, sql, servers) {
+        Ok(tokens) => tokens,
+        Err(_) => return Ok(None),
+    };
+    let words: Vec<(usize, &Token)> = tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| !matches!(t, Token::Whitespace(_) | Token::SemiColon))
+        .collect();
+    let is = |token: &Token, keyword: &str| matches!(token, Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(keyword));
+    if words.len() < 2 || !is(words[1].1, "ACCOUNT") {
+        return Ok(None);
+    }
+    let lock = match words[0].1 {
+        word if is(word, "LOCK") => true,
+        word if is(word, "UNLOCK") => false,
+        _ => return Ok(None),
+    };
+    let mut query = SqldapQuery::new();
+    query.qtype = QueryType::UPDATE;
+    let dns = target_entries(&tokens, &words[2..], servers, &mut query, ACCOUNT_USAGE)?;
+    query.changes = account::changes(servers, &query.server, dns, lock)?;
+    Ok(Some(query))
+}
+
+// The entries of the target of ALTER ENTRY, LOCK ACCOUNT and UNLOCK
+// ACCOUNT: a quoted dn or @people WHERE uid=jdoe, which is searched. Sets
+// the server and table of the query.
+fn target_entries(
+    tokens: &[Token],
+    target: &[(usize, &Token)],
+    servers: &mut Servers,
+    query: &mut SqldapQuery,
+    usage: &str,
+) -> Result<Vec<String>> {
+    query.server = servers.default.clone();
+    if let [(_, dn)] = target {
+        if let Some(dn) = string(dn) {
+            return Ok(vec![with_base(dn.to_string(), &servers.session.base)]);
+        }
+    }
+    if target.is_empty() {
+        bail!(usage.to_string());
+    }
+    // @people WHERE uid=jdoe is the rest of a DELETE FROM statement, its
+    // tokens are parsed as such (printing them would lose the escaping of
+    // quotes in strings)
+    let mut statement = vec![
+        Token::make_keyword("DELETE"),
+        Token::Whitespace(Whitespace::Space),
+        Token::make_keyword("FROM"),
+        Token::Whitespace(Whitespace::Space),
+    ];
+    statement.extend_from_slice(&tokens[target[0].0..=target[target.len() - 1].0]);
+    let mut parser = Parser::new(statement);
+    let (table_name, selection) = match parser.parse_statement().context("Cannot parse sql")? {
+        Statement::Delete {
+            table_name,
+            selection,
+        } if parser.peek_token() == Token::EOF => (table_name, selection),
+        _ => bail!(usage.to_string()),
+    };
+    let table = parse_write_table(table_name, servers)?;
+    query.server = table.server;
+    query.table = table.table;
+    query.unfiltered = selection.is_none();
+    query.filter = write_filter(selection)?;
+    let target = select::Target {
+        server: &query.server,
+        base: &query.table,
+        scope: table.scope,
+        deref: table.deref,
+        referrals: table.referrals,
+    };
+    let mut dns: Vec<String> =
+        select::search(servers, &target, &query.filter, vec!["1.1".to_string()])?
+            .complete()?
+            .into_iter()
+            .map(|entry| entry.dn)
+            .collect();
+    dns.sort();
+    dns.dedup();
+    Ok(dns)
+}
+
+// FROM "ou=people" after SET base = 'dc=example,dc=com' searches
+// ou=people,dc=example,dc=com.
+fn with_base(table: String, base: &Option<String>) -> String {
+    let base = match base {
+        Some(base) => base,
+        None => return table,
+    };
+    let normalize = |dn: &str| dn::normalize(dn).unwrap_or_else(|_| dn.to_lowercase());
+    let (normalized, normalized_base) = (normalize(&table), normalize(base));
+    if normalized == normalized_base || normalized.ends_with(&format!(",{}", normalized_base)) {
+        table
+    } else {
+        format!("{},{}", table, base)
+    }
+}
+
+fn parse_select(s: Select, servers: &mut Servers, ctes: &Ctes) -> Result<SqldapQuery> {
+    let mut query = SqldapQuery::new();
+    let s = view::expand(s, servers)?;
+
+    for expr in s.projection {
+        match expr {
+            SelectItem::UnnamedExpr(expr) => {
+                match expr {
+                    Expr::Identifier(ref ident) => {
+                        query.columns.push((ident.to_string(), expr.clone()))
+                    } //println!("--> {}", ident),
+                    Expr::CompoundIdentifier(ref idents) => {
+                        let name = idents.last().map(|i| i.value.clone()).unwrap_or_default();
+                        query.columns.push((name, expr.clone()))
+                    }
+                    Expr::Function(_) => query.columns.push((expr.to_string(), expr.clone())),
+                    _ => bail!("Unsupported SelectItem::UnnamedExpr(expr)"),
+                }
+            }
+            SelectItem::ExprWithAlias { expr, alias } => query.columns.push((alias.value, expr)),
+            SelectItem::Wildcard => query.wildcard = true,
+            _ => bail!("Unsupported SelectItem"),
+        }
+    }
+    // g.cn and p.cn can't both be called cn
+    let names: Vec<String> = query
+        .columns
+        .iter()
+        .map(|(n, _)| n.to_lowercase())
+        .collect();
+    for (name, expr) in query.columns.iter_mut() {
+        if names.iter().filter(|n| **n == name.to_lowercase()).count() > 1 {
+            if let Expr::CompoundIdentifier(_) = expr {
+                *name = expr.to_string();
+            }
+        }
+    }
+
+    for expr in s.group_by {
+        // GROUP BY can refer to a column alias
+        let expr = match &expr {
+            Expr::Identifier(ident) => query
+                .columns
+                .iter()
+                .find(|(name, _)| name == &ident.value)
+                .map(|(_, e)| e.clone())
+                .unwrap_or(expr),
+            _ => expr,
+        };
+        query.group_by.push(expr);
+    }
+
+    let mut from = s.from;
+    if from.len() > 1 {
+        bail!("Only one table is supported in FROM, use JOIN to combine tables");
+    }
+    let joins = if from.is_empty() {
+        // SELECT uid WHERE ... searches the base of SET base
+        query.server = servers.default.clone();
+        query.table = match &servers.session.base {
+            Some(base) => base.clone(),
+            None => bail!("FROM is missing, add a table or use SET base = 'dc=...' first"),
+        };
+        Vec::new()
+    } else {
+        let from = from.remove(0);
+        let from_table = parse_table(from.relation, servers)?;
+        query.rows = ctes.get(&from_table.table.to_lowercase()).cloned();
+        query.server = from_table.server;
+        query.table = if from_table.configured || query.rows.is_some() {
+            from_table.table
+        } else {
+            with_base(from_table.table, &servers.session.base)
+        };
+        query.alias = from_table.alias;
+        query.scope = from_table.scope;
+        query.deref = from_table.deref;
+        query.referrals = from_table.referrals;
+        from.joins
+    };
+    for join in joins {
+        let TableRef {
+            server,
+            table,
+            alias,
+            scope,
+            deref,
+            referrals,
+            configured,
+        } = parse_table(join.relation, servers)?;
+        if ctes.contains_key(&table.to_lowercase()) {
+            bail!("{} can only be used as the first table in FROM", table);
+        }
+        let table = if configured {
+            table
+        } else {
+            with_base(table, &servers.session.base)
+        };
+        // examplead.people JOIN exampleldap.people needs aliases
+        if alias == query.alias || query.joins.iter().any(|t| t.alias == alias) {
+            bail!(
+                "Table alias {} is used more than once, add an alias: JOIN ... AS x",
+                alias
+            );
+        }
+        let (on, outer) = match join.join_operator {
+            JoinOperator::Inner(JoinConstraint::On(on)) => (on, false),
+            JoinOperator::LeftOuter(JoinConstraint::On(on)) => (on, true),
+            _ => bail!("Only JOIN ... ON and LEFT JOIN ... ON are supported"),
+        };
+        query.joins.push(JoinedTable {
+            alias,
+            server,
+            table,
+            scope,
+            deref,
+            referrals,
+            filter: String::from(""),
+            identifiers: Vec::new(),
+            on,
+            outer,
+        });
+    }
+
+    if query.joins.is_empty() {
+        for (_, expr) in query.columns.iter_mut() {
+            *expr = eval::unqualify(expr, &query.alias);
+        }
+        for expr in query.group_by.iter_mut() {
+            *expr = eval::unqualify(expr, &query.alias);
+        }
+        //println!("->{:#?}", s.selection);
+        let selection = match s.selection {
+            Some(e) => Some(eval::unqualify(
+                &resolve_subqueries(e, servers, ctes)?,
+                &query.alias,
+            )),
+            None => None,
+        };
+        if let Some(selection) = &selection {
+            query.chunks = chunk_filters(selection)?;
+        }
+        let binary_op = match &selection {
+            Some(selection) => ldap_filter(selection)?.unwrap_or_default(),
+            None => String::from(""),
+        };
+        query.filter = binary_op;
+        if let Some(selection) = selection {
+            // the rows of a WITH table are not searched, but filtered here
+            if eval::is_client_side(&selection) || query.rows.is_some() {
+                query.selection = Some(selection);
+            }
+        }
+    } else {
+        // conditions on a single table are added to the search filter of
+        // that table, the complete WHERE clause is checked on the joined rows
+        let selection = match s.selection {
+            Some(e) => Some(resolve_subqueries(e, servers, ctes)?),
+            None => None,
+        };
+        let mut filters: HashMap<String, Vec<String>> = HashMap::new();
+        let mut add_filters = |conditions: Vec<&Expr>, only: Option<&str>| {
+            for condition in conditions {
+                let qualifiers = eval::qualifiers(condition);
+                if let Some(Some(alias)) = qualifiers.first() {
+                    if qualifiers.iter().all(|q| q.as_ref() == Some(alias))
+                        && (only.is_none() || only == Some(alias.as_str()))
+                        && !eval::is_client_side(condition)
+                    {
+                        // conditions the filter can't express are only
+                        // checked on the joined rows
+                        if let Ok(Some(filter)) = ldap_filter(&eval::unqualify(condition, alias)) {
+                            filters.entry(alias.to_string()).or_default().push(filter);
+                        }
+                    }
+                }
+            }
+        };
+        if let Some(selection) = &selection {
+            add_filters(eval::conjuncts(selection), None);
+        }
+        // ON p.uid = g.memberUid AND p.passwordretrycount>=3: the second
+        // condition also narrows the search of p, even for a LEFT JOIN
+        for table in &query.joins {
+            add_filters(eval::conjuncts(&table.on), Some(&table.alias));
+        }
+        let combine = |filters: Option<&Vec<String>>| match filters {
+            Some(f) if f.len() == 1 => f[0].clone(),
+            Some(f) => format!("(&{})", f.join("")),
+            None => String::from(""),
+        };
+        query.filter = combine(filters.get(&query.alias));
+        for table in query.joins.iter_mut() {
+            table.filter = combine(filters.get(&table.alias));
+        }
+        for table in query.joins.iter_mut() {
+            if table.filter.is_empty() {
+                table.filter = "(objectClass=*)".to_string();
+            }
+        }
+        query.selection = selection;
+    }
+
+    if !query.wildcard {
+        let mut exprs: Vec<&Expr> = query.columns.iter().map(|(_, e)| e).collect();
+        exprs.extend(query.selection.iter());
+        exprs.extend(query.joins.iter().map(|t| &t.on));
+        let requested = |alias: &str| {
+            let mut attrs = Vec::new();
+            for expr in &exprs {
+                eval::attributes(expr, alias, &mut attrs);
+            }
+            // an empty list would return all attributes,
+            // 1.1 asks the server for none (eg: SELECT dn)
+            if attrs.is_empty() {
+                attrs.push("1.1".to_string());
+            }
+            attrs
+        };
+        let identifiers = requested(&query.alias);
+        let joined: Vec<Vec<String>> = query.joins.iter().map(|t| requested(&t.alias)).collect();
+        query.identifiers = identifiers;
+        for (table, identifiers) in query.joins.iter_mut().zip(joined) {
+            table.identifiers = identifiers;
+        }
+    }
+
+    Ok(query)
+}
+
+fn parse_query(dialect: Box<dyn Dialect>, sql: &str, servers: &mut Servers) -> Result<SqldapQuery> {
+    if let Some(sql) = strip_keyword(&*dialect, sql, "EXPLAIN") {
+        let mut query = parse_query(dialect, &sql, servers)?;
+        if !query.qtype.writes() {
+            bail!("EXPLAIN is only supported for INSERT, UPDATE, DELETE and ALTER ENTRY");
+        }
+        query.explain = true;
+        return Ok(query);
+    }
+    if let Some(query) = parse_alter_entry(&*dialect, sql, servers)? {
+        return Ok(query);
+    }
+    if let Some(query) = parse_set_password(&*dialect, sql, servers)? {
+        return Ok(query);
+    }
+    if let Some(query) = parse_account(&*dialect, sql, servers)? {
+        return Ok(query);
+    }
+    let mut parser = Parser::new(tokenize(&*dialect, sql, servers)?);
+    let mut parse_result = Vec::new();
+    loop {
+        let mut delimited = parse_result.is_empty();
+        while parser.consume_token(&Token::SemiColon) {
+            delimited = true;
+        }
+        if parser.peek_token() == Token::EOF {
+            break;
+        }
+        if !delimited {
+            bail!(
+                "Cannot parse sql: expected end of statement, found {}",
+                parser.peek_token()
+            );
+        }
+        parse_result.push(parser.parse_statement().context("Cannot parse sql")?);
+    }
+
+    let mut query = SqldapQuery::new();
 
     for stmt in parse_result {
         //println!("{}", stmt);
         match stmt {
             Statement::Query(s) => {
                 query.qtype = QueryType::SELECT;
-                match s.body {
-                    SetExpr::Select(s) => {
-                        for expr in s.projection {
-                            match expr {
-                                SelectItem::UnnamedExpr(expr) => {
-                                    match expr {
-                                        Expr::Identifier(ident) => {
-                                            query.identifiers.push(ident.to_string())
-                                        } //println!("--> {}", ident),
-                                        _ => bail!("Unsupported SelectItem::UnnamedExpr(expr)"),
-                                    }
-                                }
-                                SelectItem::Wildcard => query.wildcard = true,
-                                _ => bail!("Unsupported SelectItem"),
-                            }
+                query = parse_with(*s, servers, &Ctes::new())?;
+            }
+            Statement::Update {
+                table_name,
+                assignments,
+                selection,
+            } => {
+                query.qtype = QueryType::UPDATE;
+                let table = parse_write_table(table_name, servers)?;
+                query.server = table.server;
+                query.table = table.table;
+                query.scope = table.scope;
+                query.deref = table.deref;
+                query.referrals = table.referrals;
+
+                let mut mod_vec = Vec::new();
+                // SET dn = 'uid=new,ou=other,...' or SET rdn = 'uid=new'
+                let mut new_dn = None;
+                // SET userPassword = PASSWORD('secret')
+                let mut password = None;
+                for assignment in assignments {
+                    let var = assignment.id.value;
+                    if let Expr::Function(f) = &assignment.value {
+                        if f.name.to_string().eq_ignore_ascii_case("PASSWORD") {
+                            password = Some(modify::password(&var, f)?);
+                            continue;
+                        }
+                    }
+                    if var.eq_ignore_ascii_case("dn") || var.eq_ignore_ascii_case("rdn") {
+                        let value = modify::literal(&assignment.value)?;
+                        if var.eq_ignore_ascii_case("rdn")
+                            && dn::parse(&value).map(|r| r.len()).unwrap_or(0) != 1
+                        {
+                            bail!("SET rdn = 'uid=new' takes a single rdn, use SET dn = '...' to move an entry");
+                        }
+                        new_dn = Some(value);
+                        continue;
+                    }
+                    let modification = modify::assignment(&var, &assignment.value)?;
+                    // the current values are only needed to check that
+                    // attr = 'x' doesn't overwrite the values of a list by
+                    // accident, attr = ARRAY(...) replaces them on purpose
+                    let array = matches!(assignment.value, Expr::Function(_));
+                    if matches!(modification, Replace(_, _)) && !array {
+                        query.identifiers.push(var);
+                    }
+                    mod_vec.push(modification);
+                }
+                if query.identifiers.is_empty() {
+                    query.identifiers.push("1.1".to_string());
+                }
+
+                // ldap modify works on a single dn, so the entries the WHERE
+                // clause matches are searched first and modified one by one
+                query.unfiltered = selection.is_none();
+                query.filter = write_filter(selection)?;
+
+                let target = select::Target {
+                    server: &query.server,
+                    base: &query.table,
+                    scope: query.scope,
+                    deref: query.deref,
+                    referrals: query.referrals,
+                };
+                let mut rs =
+                    select::search(servers, &target, &query.filter, query.identifiers.clone())?
+                        .complete()?;
+                // a followed referral can return an entry again
+                let mut seen = HashSet::new();
+                rs.retain(|entry| seen.insert(entry.dn.clone()));
+                if new_dn.is_some() && rs.len() > 1 {
+                    bail!(
+                        "The WHERE clause matches {} entries, SET dn and SET rdn can only rename one entry",
+                        rs.len()
+                    );
+                }
+                for entry in rs {
+                    let array = entry.attrs.iter().find(|(_, values)| values.len() > 1);
+                    if let Some((key_attr, _)) = array {
+                        println!(
+                            "ERROR: attribute {} has more than one value, use {} = {} + 'value' or {} = {} - 'value' to add or remove a value, or {} = ARRAY(...) to replace them all.",
+                            key_attr, key_attr, key_attr, key_attr, key_attr, key_attr
+                        );
+                        println!("{} will not be updated ", entry.dn);
+                        continue;
+                    }
+                    if !mod_vec.is_empty() {
+                        query.changes.push(modify::Change {
+                            dn: entry.dn.clone(),
+                            operation: modify::Operation::Modify(mod_vec.clone()),
+                        });
+                    }
+                    if let Some(password) = &password {
+                        query.changes.push(modify::Change {
+                            dn: entry.dn.clone(),
+                            operation: modify::Operation::Password(password.clone()),
+                        });
+                    }
+                    // the attributes are modified before the entry is renamed
+                    if let Some(new_dn) = &new_dn {
+                        query.changes.push(modify::Change {
+                            operation: modify::rename(&entry.dn, new_dn)?,
+                            dn: entry.dn,
+                        });
+                    }
+                }
+            }
+            Statement::Insert {
+                table_name,
+                columns,
+                source,
+            } => {
+                query.qtype = QueryType::INSERT;
+                let table = parse_write_table(table_name, servers)?;
+                query.server = table.server;
+                query.table = table.table;
+                query.alias = table.alias;
+                if columns.is_empty() {
+                    bail!("INSERT needs the attributes of the entries: INSERT INTO @people (uid, cn, ...) VALUES (...)");
+                }
+                let rows = match source.body {
+                    SetExpr::Values(values) => values.0,
+                    _ => bail!("Only INSERT ... VALUES (...) is supported."),
+                };
+                // without a dn column, the dn of an entry is its rdn
+                // attribute (people.rdn=uid in the .tables section, or else
+                // the first column) followed by the dn of the table
+                let rdn = servers
+                    .get(&query.server)
+                    .and_then(|s| s.rdns.get(&query.alias))
+                    .cloned()
+                    .unwrap_or_else(|| columns[0].value.clone());
+                for row in rows {
+                    if row.len() != columns.len() {
+                        bail!(
+                            "INSERT has {} columns, but a row has {} values",
+                            columns.len(),
+                            row.len()
+                        );
+                    }
+                    let mut dn = None;
+                    let mut attrs = Vec::new();
+                    for (column, value) in columns.iter().zip(row.iter()) {
+                        if column.value.eq_ignore_ascii_case("dn") {
+                            dn = Some(modify::literal(value)?);
+                            continue;
+                        }
+                        let values = modify::values(value)?;
+                        if !values.is_empty() {
+                            attrs.push((column.value.clone(), values));
+                        }
+                    }
+                    let dn = match dn {
+                        // uid=jdoe is relative to the table, a full dn is kept as is
+                        Some(dn) if dn::parse(&dn)?.len() == 1 => {
+                            format!("{},{}", dn, query.table)
                         }
-                        for table in s.from {
-                            match table.relation {
-                                TableFactor::Table {
-                                    name,
-                                    alias: _,
-                                    args: _,
-                                    with_hints: _,
-                                } => {
-                                    query.table = name.to_string();
-                                    query.table = query
-                                        .table
-                                        .trim_end_matches('\"')
-                                        .trim_start_matches('\"')
-                                        .to_string();
-                                    for (table, value) in &tables {
-                                        query.table =
-                                            query.table.replace(&format!("@{}", table), value);
-                                    }
+                        Some(dn) => dn,
+                        None => {
+                            let value = attrs
+                                .iter()
+                                .find(|(attr, _)| attr.eq_ignore_ascii_case(&rdn))
+                                .filter(|(_, values)| values.len() == 1)
+                                .and_then(|(_, values)| values.iter().next());
+                            match value {
+                                Some(value) => {
+                                    format!("{}={},{}", rdn, dn::escape_value(value), query.table)
                                 }
-                                _ => bail!("Unsupported TableFactor"),
+                                None => bail!(
+                                    "A row has no single {} value to build its dn from, add a dn column",
+                                    rdn
+                                ),
                             }
                         }
-                        //println!("->{:#?}", s.selection);
-                        let binary_op = if s.selection.is_some() {
-                            let mut result = parse_selection(s.selection.unwrap())
-                                .context("Cannot parse selection")?;
-                            if !result.starts_with('(') {
-                                result = format!("({})", result);
-                            }
-                            result
-                        } else {
-                            String::from("")
-                        };
-                        query.filter = binary_op;
-                    }
-                    _ => bail!("Unsupported Statement::Query(s)"),
+                    };
+                    query.changes.push(modify::Change {
+                        dn,
+                        operation: modify::Operation::Add(attrs),
+                    });
                 }
             }
-            //Statement::Update { table_name: table_name, assignments: assignments, selection: selection } => {
-            Statement::Update {
+            Statement::Delete {
                 table_name,
-                assignments,
                 selection,
             } => {
-                query.qtype = QueryType::UPDATE;
-                //println!("{:#?}", table_name);
-                //println!("{:#?}", assignments);
-                //println!("{:#?}", selection);
-                //for table in table_name {
-                match table_name {
-                    sqlparser::ast::ObjectName(ident) => {
-                        for i in ident {
-                            match i {
-                                Ident {
-                                    value: val,
-                                    quote_style: _,
-                                } => {
-                                    println!("table_name: {}", &val);
-                                    //query.var = val;
-                                    query.table = val;
-                                }
-                            }
-                        }
-                    }
+                query.qtype = QueryType::DELETE;
+                let table = parse_write_table(table_name, servers)?;
+                query.server = table.server;
+                query.table = table.table;
+                query.unfiltered = selection.is_none();
+                query.filter = write_filter(selection)?;
+                let target = select::Target {
+                    server: &query.server,
+                    base: &query.table,
+                    scope: table.scope,
+                    deref: table.deref,
+                    referrals: table.referrals,
+                };
+                let mut dns: Vec<String> =
+                    select::search(servers, &target, &query.filter, vec!["1.1".to_string()])?
+                        .complete()?
+                        .into_iter()
+                        .map(|entry| entry.dn)
+                        .collect();
+                dns.sort();
+                dns.dedup();
+                // entries below other entries that are deleted go first
+                dns.sort_by_key(|dn| {
+                    std::cmp::Reverse(dn::parse(dn).map(|r| r.len()).unwrap_or(0))
+                });
+                let subtree = servers.session.subtree_delete;
+                for dn in dns {
+                    query.changes.push(modify::Change {
+                        dn,
+                        operation: modify::Operation::Delete { subtree },
+                    });
                 }
-                let mut mod_vec = Vec::new();
-                for assignment in assignments {
-                    //println!("{:#?}", parse_selection(assignment.value));
-                    //println!("{:#?} {:#?}", assignment.id, assignment.value);
-                    //let mut var = String::from("");
-                    let mut val = HashSet::new();
-                    let var = match assignment.id {
-                        Ident {
-                            value: val,
-                            quote_style: _,
-                        } => {
-                            query.identifiers.push(val.to_string());
-                            //println!("SET: {}", val);
-                            format!("{}", val)
-                            //query.var = val;
-                        }
-                    };
-                    let newval = match assignment.value {
-                        Expr::Value(value) => {
-                            value.to_string()
-                            //let tmp = value.to_string().clone();
-                            //println!("{}", &value);
-                        }
-                        Expr::Identifier(ident) => {
-                            ident.to_string()
-                            //let tmp = ident.to_string().clone();
-                            //val.insert(tmp.as_str());
-                            //println!("{}", &ident);
-                        }
-                        _ => bail!("Only Value and Identifier are supported."),
-                    };
-                    val.insert(format!("{}", newval));
-                    mod_vec.push(Replace(format!("{}", &var), val));
-                }
-                query.identifiers.push("entrydn".to_string());
-                //}
-                //println!("->{:#?}", s.selection);
-                // THIS SHOULD BE EMPTY
-                // as ldap modify is not compatible with an sql where clause
-                // the table_name should be the full dn
-                // so where uid=username cannot be used, the table_name should be
-                // uid=username,ou=group,ou=people,o=department,dc=example,dc=com
-
-                // OR
-
-                // the only way around this is first do a query for all entries
-                // where uid=username or uidnumber>=10 or whatever (with a possible wildcard)
-                // get the full dns for these entries, and then run ldap modify on all of
-                // them through a loop
-
-                let binary_op = if selection.is_some() {
-                    let mut result =
-                        parse_selection(selection.unwrap()).context("Cannot parse selection")?;
-                    if !result.starts_with('(') {
-                        result = format!("({})", result);
-                    }
-                    result
-                } else {
-                    String::from("")
+            }
+            Statement::SetVariable {
+                local: _,
+                variable,
+                value,
+            } => {
+                query.qtype = QueryType::SET;
+                let value = match value {
+                    SetVariableValue::Ident(ident) => ident.value,
+                    SetVariableValue::Literal(value) => eval::literal(&value).unwrap_or_default(),
                 };
-                //println!("->{:#?}", &binary_op);
-                query.filter = binary_op;
-
-                //println!("{:#?}", query.identifiers);
-                let (rs, _res) = ldap
-                    .search(
-                        &query.table,
-                        Scope::Subtree,
-                        &query.filter,
-                        query.identifiers.clone(),
-                    )?
-                    .success()
-                    .context("Ldap search failed")?;
-
-                //let mut result = Vec::new();
-                //let mut key = String::from("");
-
-                //let mut mod_vec_update = Vec::new();
-                for entry in rs {
-                    let mut got_error = false;
-                    let se = SearchEntry::construct(entry);
-
-                    let mut entrydn = String::from("");
-                    for a in se.attrs {
-                        let (key_attr, val_attr) = a;
-
-                        if val_attr.len() > 1 {
-                            println!("ERROR: attribute {} is an array, which cannot be modified. Please use delete or add.", &key_attr);
-                            // REMARK: maybe we should get for example all memberOf values, store them in a Vec, update
-                            // the value we want to update and send this in the Replace mod
-                            /*
-                            for i in mod_vec.iter() {
-                                match i {
-                                    Mod::Replace(key, val) => {
-                                        if key == &key_attr {
-                                            println!("key: {}", key);
-                                            mod_vec_update.push(Replace(format!("{}", &key), val));
-                                        } else {
-                                            mod_vec_update.push(Replace(format!("{}", &key), val));
-                                        }
-                                    }
-                                    _ => println!("hoi"),
-                                }
-                            }
-                            */
-                            got_error = true;
+                let session = &mut servers.session;
+                match variable.value.to_lowercase().as_str() {
+                    "sizelimit" => {
+                        session.sizelimit =
+                            Some(server::parse_limit(&value).context("Wrong sizelimit")?)
+                    }
+                    "timelimit" => {
+                        session.timelimit =
+                            Some(server::parse_limit(&value).context("Wrong timelimit")?)
+                    }
+                    "output" => session.output = server::parse_output(&value)?,
+                    "subtreedelete" => {
+                        session.subtree_delete =
+                            server::parse_switch(&value).context("Wrong subtreedelete")?
+                    }
+                    "base" => {
+                        session.base = if value.is_empty() || value.eq_ignore_ascii_case("DEFAULT")
+                        {
+                            None
                         } else {
-                            if key_attr == "entrydn" {
-                                entrydn = val_attr[0].clone();
-                                //println!("{} -> {:?} ", &val_attr[0], mod_vec);
-                                /*
-
-                                let res = ldap
-                                        .with_controls(RelaxRules.critical())
-                                        .modify("uid=inejge,ou=People,dc=example,dc=org", mod_vec)?
-                                        .success()?;
-
-                                                            */
-                            }
+                            Some(value.clone())
                         }
                     }
-                    if !got_error {
-                        println!("updating: {} -> {:?} ", &entrydn, mod_vec);
-                        println!("---------------");
-                    } else {
-                        println!("{} will not be updated ", &entrydn);
-                    }
+                    // the statements are run on another server by main()
+                    "server" => {}
+                    _ => bail!(
+                        "Unknown variable {}, use server, output, base, sizelimit, timelimit or subtreedelete",
+                        variable.value
+                    ),
                 }
-
-                //println!("{:#?}", result);
+                query.var = variable.value.to_lowercase();
+                query.value = value;
+            }
+            Statement::StartTransaction { .. } => {
+                query.qtype = QueryType::TRANSACTION;
+                query.var = String::from("begin");
+            }
+            Statement::Commit { .. } => {
+                query.qtype = QueryType::TRANSACTION;
+                query.var = String::from("commit");
+            }
+            Statement::Rollback { .. } => {
+                query.qtype = QueryType::TRANSACTION;
+                query.var = String::from("rollback");
             }
             Statement::ShowVariable { variable: var } => {
                 query.qtype = QueryType::SHOW;
-                //println!("{:?}", var);
-                match var {
-                    Ident {
-                        value: val,
-                        quote_style: _,
-                    } => {
-                        //println!("{}", val);
-                        query.var = val;
-                    }
-                }
+                query.var = var.value;
             }
-            _ => bail!("Only SELECT AND UPDATE (work in progress) queries are supported."),
+            _ => bail!(
+                "Only SELECT, INSERT, UPDATE AND DELETE (work in progress) queries are supported."
+            ),
         }
     }
 
@@ -412,29 +1617,13 @@ fn parse_query(
 }
 
 fn get_config_cwd(filename: &str) -> Option<String> {
-    let cwd = match std::env::current_dir() {
-        Ok(path) => Some(path.display().to_string()),
-        Err(_e) => None,
-    };
-
-    if cwd.is_some() {
-        return Some(format!("{}/{}", cwd.unwrap(), filename));
-    }
-
-    None
+    std::env::current_dir()
+        .ok()
+        .map(|path| format!("{}/{}", path.display(), filename))
 }
 
 fn get_config_home(filename: &str) -> Option<String> {
-    let home = match home::home_dir() {
-        Some(path) => Some(path.display().to_string()),
-        None => None,
-    };
-
-    if home.is_some() {
-        return Some(format!("{}/.{}", home.unwrap(), filename));
-    }
-
-    None
+    home::home_dir().map(|path| format!("{}/.{}", path.display(), filename))
 }
 
 fn get_config_etc(filename: &str) -> Option<String> {
@@ -533,6 +1722,169 @@ fn fits_on_screen(table_data: &str) -> bool {
     true
 }
 
+// Picks the servers the statements run on: a section of sqldap.ini, '*' or
+// ALL for all of them, or the first server when no name is given.
+fn choose_servers(servers: &mut Servers, name: &str) -> Result<(bool, Vec<String>)> {
+    let names = servers.names();
+    let federated = name == "*" || name.eq_ignore_ascii_case("ALL");
+    let name = if name.is_empty() || federated {
+        names.first().cloned().unwrap_or_default()
+    } else {
+        name.to_string()
+    };
+    servers
+        .get(&name)
+        .and_then(|server| server.connection.as_ref())
+        .context(format!(
+            "Key 'connection' in section {} could not be found.",
+            name
+        ))?;
+    servers.default = name.clone();
+    if federated {
+        Ok((true, names))
+    } else {
+        Ok((false, vec![name]))
+    }
+}
+
+fn print_servers(servers: &Servers, federated: bool, targets: &[String]) {
+    if federated {
+        println!("Using all servers: {}\n", targets.join(", "));
+    } else if let Some(server) = servers.current() {
+        println!(
+            "Using server: {} ({})\n",
+            server.name,
+            server.connection.clone().unwrap_or_default()
+        );
+    }
+}
+
+// Lists the referrals of the last statement that were not followed.
+fn print_referrals(servers: &mut Servers) {
+    if servers.referrals.is_empty() {
+        return;
+    }
+    println!("\nThe directory refers to other servers for part of the results:");
+    let mut seen = HashSet::new();
+    for url in servers.referrals.drain(..) {
+        if seen.insert(url.clone()) {
+            println!("  {}", url);
+        }
+    }
+    println!("Use WITH (REFERRALS=FOLLOW) or referrals=follow in sqldap.ini to follow them.");
+}
+
+// Quotes a csv field when it contains a separator, quote or newline.
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// SET output = csv: one line per row, the values of multi-valued
+// attributes are separated by a newline within their field.
+fn print_csv(headers: &[String], records: &[Record], wildcard: bool) {
+    let mut headers = headers.to_vec();
+    if wildcard {
+        let mut keys: Vec<String> = records
+            .iter()
+            .flat_map(|r| r.attrs.keys().cloned())
+            .filter(|k| !headers.contains(k))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        headers.extend(keys);
+    }
+    let line: Vec<String> = headers.iter().map(|h| csv_field(h)).collect();
+    println!("{}", line.join(","));
+    for record in records {
+        let line: Vec<String> = headers
+            .iter()
+            .map(|h| {
+                csv_field(
+                    &record
+                        .attrs
+                        .get(h)
+                        .map(|v| v.join("\n"))
+                        .unwrap_or_default(),
+                )
+            })
+            .collect();
+        println!("{}", line.join(","));
+    }
+}
+
+// Asks whether the changes of a statement should be made.
+fn confirm(count: usize, server: &str) -> Result<bool> {
+    print!("Change {} entries on {}? [y/N] ", count, server);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .context("Cannot read the answer")?;
+    println!();
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// Makes the changes of a statement, after asking when they change more
+// entries than the server allows. The old values are saved first.
+fn write(
+    servers: &mut Servers,
+    server: &str,
+    changes: Vec<modify::Change>,
+    yes: bool,
+    transaction: &mut Option<Transaction>,
+) -> Result<()> {
+    // an entry with several changes is counted once
+    let mut seen = HashSet::new();
+    let dns: Vec<&str> = changes
+        .iter()
+        .map(|c| c.dn.as_str())
+        .filter(|dn| seen.insert(*dn))
+        .collect();
+    let ask = dns.len() > servers.confirm(server)? && !yes;
+    let deletes = changes
+        .iter()
+        .all(|c| matches!(c.operation, modify::Operation::Delete { .. }));
+    if (deletes || ask) && !dns.is_empty() {
+        if deletes {
+            println!("The following entries will be deleted:");
+        } else {
+            println!("The following entries will be changed:");
+        }
+        for dn in &dns {
+            println!("  {}", dn);
+        }
+        println!();
+    }
+    if ask && !confirm(dns.len(), server)? {
+        println!("Nothing was changed.\n");
+        if let Some(transaction) = transaction {
+            transaction.fail(servers)?;
+        }
+        return Ok(());
+    }
+    if changes.is_empty() {
+        return modify::apply(servers, server, changes).map(|_| ());
+    }
+    let backup = backup::save(servers, server, &changes)?;
+    let done = modify::apply(servers, server, changes)?;
+    match transaction {
+        Some(transaction) => transaction.add(servers, backup, done),
+        None => backup.finish(&done),
+    }
+}
+
+// Removes an option like --dry-run from the arguments, true when it was
+// given.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let count = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != count
+}
+
 fn main() -> Result<()> {
     SimpleLogger::new()
         .with_level(ldap3::log::LevelFilter::Error)
@@ -545,18 +1897,60 @@ fn main() -> Result<()> {
 
     // now parse command line args
     // first arg should be the name of the section in the config file
-    let tmpsql = match std::env::args().nth(1) {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // show the changes of the statements instead of making them
+    let dry_run = take_flag(&mut args, "--dry-run");
+    let yes = take_flag(&mut args, "--yes");
+    let force = take_flag(&mut args, "--force");
+    if args.first().map(|a| a.as_str()) == Some("undo") {
+        let file = args.get(1).context(USAGE)?;
+        let text = std::fs::read_to_string(file).context(format!("Cannot read {}", file))?;
+        let changes = ldif::parse(&text).context(format!("Cannot read the changes of {}", file))?;
+        // the server the backup was made on, unless another one is given
+        let name = match args.get(2) {
+            Some(name) => name.clone(),
+            None => backup::server(&text).unwrap_or_default(),
+        };
+        let mut servers = Servers::new(&conf, "");
+        let (federated, targets) = choose_servers(&mut servers, &name)?;
+        if federated {
+            bail!("Changes can't be made on all servers, name the server instead of '*'.");
+        }
+        print_servers(&servers, federated, &targets);
+        if dry_run {
+            modify::explain(&changes);
+        } else {
+            write(&mut servers, &targets[0], changes, yes, &mut None)?;
+        }
+        servers.unbind()?;
+        return Ok(());
+    }
+    let tmpsql = match args.first() {
         //.context(USAGE)?;
-        Some(sql) => sql,
+        Some(sql) => sql.clone(),
         None => {
             println!("{}", USAGE);
             std::process::exit(0);
         }
     };
-    let mut servername = match std::env::args().nth(2) {
-        Some(n) => n,
-        None => String::from(""),
-    }; //.context(USAGE)?;
+    // the other arguments are the server (a section of sqldap.ini, '*' or
+    // ALL) and the values of the :parameters of the query, either as
+    // name=value or in the order the parameters appear in the query
+    let mut servers = Servers::new(&conf, "");
+    let mut servername = String::from("");
+    let mut parameters = Parameters::default();
+    for arg in args.into_iter().skip(1) {
+        if let Some((name, value)) = Parameters::assignment(&arg) {
+            parameters.named.insert(name, value);
+        } else if servername.is_empty()
+            && parameters.positional.is_empty()
+            && (servers.get(&arg).is_some() || arg == "*" || arg.eq_ignore_ascii_case("ALL"))
+        {
+            servername = arg;
+        } else {
+            parameters.positional.push(arg);
+        }
+    }
 
     // if we have received an sql file instead of a direct query
     // read the file
@@ -582,8 +1976,7 @@ fn main() -> Result<()> {
         passwordretrycount=SELECT uid FROM @people WHERE passwordretrycount>=3
     */
 
-    let sql = sql.replace(";\n", ";");
-    let mut sql = sql.split(';').collect::<Vec<&str>>();
+    let mut sql = statements(&sql);
 
     // search for queries starting with '@'
     let mut found_at = false;
@@ -593,371 +1986,496 @@ fn main() -> Result<()> {
         }
     }
 
-    let mut connection = None;
-    let mut binddn = None;
-    let mut bindpassword = None;
-    let mut tables = HashMap::new();
-
-    for (sec, prop) in &conf {
-        if servername.is_empty() && sec.is_some() {
-            servername = sec.unwrap().to_string();
-        }
-        if sec.is_some() && servername == sec.unwrap() {
-            for (key, value) in prop.iter() {
-                if key == "connection" {
-                    connection = Some(value)
+    let (mut federated, mut targets) = choose_servers(&mut servers, &servername)?;
+    servername = servers.default.clone();
+    let queries = servers
+        .current()
+        .map(|s| s.queries.clone())
+        .unwrap_or_default();
+
+    // replace @somequery with the preconfigured query from sqldap.ini
+    if found_at {
+        for (key, value) in &queries {
+            for query in sql.iter_mut() {
+                if *query == format!("@{}", key) {
+                    *query = value;
                 }
-                if key == "binddn" {
-                    binddn = Some(value)
-                }
-                if key == "bindpassword" {
-                    bindpassword = Some(value)
-                }
-            }
-        }
-        if sec.is_some() && format!("{}.tables", servername) == sec.unwrap() {
-            for (key, value) in prop.iter() {
-                tables.insert(key.to_string(), value.to_string());
             }
         }
+    }
 
-        // replace @somequery with the preconfigured query from sqldap.ini
-        if found_at && sec.is_some() && format!("{}.queries", servername) == sec.unwrap() {
-            for (key, value) in prop.iter() {
-                for query in sql.iter_mut() {
-                    if *query == format!("@{}", key) {
-                        *query = value;
+    for query in sql.iter() {
+        parameters.bind(query)?;
+    }
+    if let Some(value) = parameters.positional.first() {
+        bail!(
+            "{} is not a configured server and the query has no parameter left to fill in with it.",
+            value
+        );
+    }
+    servers.session.parameters = parameters.named;
+
+    print_servers(&servers, federated, &targets);
+    // BEGIN starts a transaction, when a statement fails before COMMIT it is
+    // rolled back
+    let mut transaction: Option<Transaction> = None;
+    let mut run = || -> Result<()> {
+        for sqlquery in sql.iter() {
+            if sqlquery.is_empty() {
+                continue;
+            }
+            /*
+            let dialect: Box<dyn Dialect> = match std::env::args().nth(3).unwrap_or_default().as_ref() {
+                "--ansi" => Box::new(AnsiDialect {}),
+                "--postgres" => Box::new(PostgreSqlDialect {}),
+                "--ms" => Box::new(MsSqlDialect {}),
+                "--generic" | "" => Box::new(GenericDialect {}),
+                s => panic!("Unexpected parameter: {}", s),
+            };
+            */
+            //let query =
+            //   parse_query(dialect, &sqlquery, tables.clone()).context("Query is not supported")?;
+
+            let mut query = None;
+            let mut records = Vec::new();
+            for name in &targets {
+                servers.default = name.to_string();
+                let dialect = Box::new(GenericDialect {});
+                let parsed = match parse_query(dialect, sqlquery, &mut servers) {
+                    Ok(parsed) => parsed,
+                    // servers without the table are left out
+                    Err(e) if federated && e.chain().any(|c| c.is::<TableNotConfigured>()) => {
+                        continue
+                    }
+                    Err(e) => return Err(e.context("Query is not supported")),
+                };
+                if parsed.qtype == QueryType::SELECT {
+                    let found = select::run(&mut servers, &parsed)?;
+                    // the entries found before a limit was hit are shown
+                    for message in &found.limited {
+                        println!("{}\n", message);
+                    }
+                    for mut record in found.records {
+                        if federated {
+                            record
+                                .attrs
+                                .insert("server".to_string(), vec![name.to_string()]);
+                        }
+                        records.push(record);
                     }
+                } else if parsed.qtype.writes() && federated {
+                    bail!("Changes can't be made on all servers, name the server instead of '*'.");
+                }
+                let select = parsed.qtype == QueryType::SELECT;
+                query = Some(parsed);
+                // SHOW is the same for every server
+                if !select {
+                    break;
                 }
             }
-        }
-    }
+            servers.default = servername.clone();
+            let query = match query {
+                Some(query) => query,
+                None => {
+                    println!(
+                        "None of the servers has the tables of query '{}'.",
+                        sqlquery
+                    );
+                    continue;
+                }
+            };
 
-    let connection = connection.context(format!(
-        "Key 'connection' in section {} could not be found.",
-        servername
-    ))?;
+            if query.qtype == QueryType::TRANSACTION {
+                if dry_run {
+                    continue;
+                }
+                if query.var == "begin" {
+                    if transaction.is_some() {
+                        bail!("BEGIN inside a transaction, COMMIT or ROLLBACK first.");
+                    }
+                    if federated {
+                        bail!(
+                            "A transaction can't span all servers, name the server instead of '*'."
+                        );
+                    }
+                    transaction = Some(transaction::begin(&mut servers, &servername)?);
+                } else {
+                    match transaction.take() {
+                        Some(transaction) if query.var == "commit" => {
+                            transaction.commit(&mut servers)?
+                        }
+                        Some(transaction) => transaction.rollback(&mut servers)?,
+                        None => bail!("{} without BEGIN.", query.var.to_uppercase()),
+                    }
+                }
+                continue;
+            }
 
-    println!("Using server: {} ({})\n", servername, connection);
-    for sqlquery in sql.iter() {
-        if sqlquery.is_empty() || sqlquery == &"\n" {
-            continue;
-        }
-        let dialect = Box::new(GenericDialect {});
-        /*
-        let dialect: Box<dyn Dialect> = match std::env::args().nth(3).unwrap_or_default().as_ref() {
-            "--ansi" => Box::new(AnsiDialect {}),
-            "--postgres" => Box::new(PostgreSqlDialect {}),
-            "--ms" => Box::new(MsSqlDialect {}),
-            "--generic" | "" => Box::new(GenericDialect {}),
-            s => panic!("Unexpected parameter: {}", s),
-        };
-        */
-        //let query =
-        //   parse_query(dialect, &sqlquery, tables.clone()).context("Query is not supported")?;
+            if let Some(transaction) = &transaction {
+                if transaction.failed() && query.qtype.writes() {
+                    println!(
+                        "Skipped, the transaction was rolled back: {}\n",
+                        sqlquery.trim()
+                    );
+                    continue;
+                }
+                if query.qtype == QueryType::SET && query.var == "server" {
+                    bail!("The server can't be switched inside a transaction, COMMIT first.");
+                }
+                if query.qtype.writes() && query.server != transaction.server {
+                    bail!(
+                        "The transaction is on {}, {} can't be changed inside it.",
+                        transaction.server,
+                        query.server
+                    );
+                }
+                // a new password is set right away and has no backup to
+                // roll back to
+                if query
+                    .changes
+                    .iter()
+                    .any(|c| matches!(c.operation, modify::Operation::Password(_)))
+                {
+                    bail!("Passwords can't be set inside a transaction, COMMIT first.");
+                }
+            }
 
-        //let mut ldap = LdapConn::new(connection).context("Cannot connect to LDAP")?;
-        let mut ldap = LdapConn::with_settings(LdapConnSettings::new().set_no_tls_verify(true),connection).context("Cannot connect to LDAP")?;
-        if binddn.is_some() && bindpassword.is_some() {
-            let _res = ldap
-                .simple_bind(binddn.unwrap(), bindpassword.unwrap())?
-                .success()
-                .context("ldap simple_bind failed")?;
-        }
+            if query.qtype == QueryType::SET {
+                if query.var == "server" {
+                    let (all, names) = choose_servers(&mut servers, &query.value)?;
+                    federated = all;
+                    targets = names;
+                    servername = servers.default.clone();
+                    print_servers(&servers, federated, &targets);
+                } else {
+                    println!("{} = {}\n", query.var, query.value);
+                }
+                continue;
+            }
 
-        let query = parse_query(dialect, &sqlquery, tables.clone(), &mut ldap)
-            .context("Query is not supported")?;
+            if query.qtype.writes() {
+                if dry_run || query.explain {
+                    modify::explain(&query.changes);
+                } else {
+                    if query.unfiltered && !force {
+                        bail!("UPDATE and DELETE without WHERE change every entry of the table, add a WHERE clause or use --force.");
+                    }
+                    write(
+                        &mut servers,
+                        &query.server,
+                        query.changes,
+                        yes,
+                        &mut transaction,
+                    )?;
+                }
+                print_referrals(&mut servers);
+                continue;
+            }
 
-        if query.qtype == QueryType::SHOW {
-            if query.var.to_uppercase() == "TABLES" {
-                if !tables.is_empty() {
+            if query.qtype == QueryType::SHOW {
+                if query.var.to_uppercase() == "TABLES" {
+                    let mut tables: Vec<(String, String)> = Vec::new();
+                    if let Some(server) = servers.current() {
+                        tables.extend(server.tables.clone());
+                        // views are listed with their query
+                        tables.extend(server.views.clone());
+                    }
+                    if !tables.is_empty() {
+                        let mut table = Table::new();
+                        table.style = TableStyle::simple();
+                        table.add_row(Row::new(vec![
+                            TableCell::new_with_alignment("Table name", 1, Alignment::Left),
+                            TableCell::new_with_alignment("Configured dn", 1, Alignment::Left),
+                        ]));
+
+                        for (key, value) in &tables {
+                            //println!("{} ({})", table, value);
+                            table.add_row(Row::new(vec![
+                                TableCell::new_with_alignment(key, 1, Alignment::Left),
+                                TableCell::new_with_alignment(value, 1, Alignment::Left),
+                            ]));
+                        }
+                        if sql.len() > 1 {
+                            println!("Results for query '{}': \n", sqlquery);
+                        }
+                        println!("{}", table.render());
+                    } else {
+                        println!("No tables are configured in your config file.");
+                        println!("See example config file below.\n");
+                        println!("{}", CONFIG_FILE_EXAMPLE);
+                    }
+                } else if query.var.to_uppercase() == "DATABASES" {
                     let mut table = Table::new();
                     table.style = TableStyle::simple();
-                    let mut row = Vec::new();
-                    row.push(TableCell::new_with_alignment(
-                        "Table name",
-                        1,
-                        Alignment::Left,
-                    ));
-                    row.push(TableCell::new_with_alignment(
-                        "Configured dn",
-                        1,
-                        Alignment::Left,
-                    ));
-                    table.add_row(Row::new(row));
-
-                    for (key, value) in &tables {
-                        //println!("{} ({})", table, value);
-                        let mut row = Vec::new();
-                        row.push(TableCell::new_with_alignment(key, 1, Alignment::Left));
-                        row.push(TableCell::new_with_alignment(value, 1, Alignment::Left));
-                        table.add_row(Row::new(row));
+                    table.add_row(Row::new(vec![
+                        TableCell::new_with_alignment("Database name", 1, Alignment::Left),
+                        TableCell::new_with_alignment("Configured server", 1, Alignment::Left),
+                    ]));
+
+                    for (sec, prop) in &conf {
+                        let sec = match sec {
+                            Some(sec) => sec,
+                            None => continue,
+                        };
+                        if !sec.contains(".tables")
+                            && !sec.contains(".views")
+                            && !sec.contains(".queries")
+                        {
+                            let mut row =
+                                vec![TableCell::new_with_alignment(sec, 1, Alignment::Left)];
+                            for (key, value) in prop.iter() {
+                                if key == "connection" {
+                                    row.push(TableCell::new_with_alignment(
+                                        value,
+                                        1,
+                                        Alignment::Left,
+                                    ));
+                                }
+                            }
+                            table.add_row(Row::new(row));
+                            //println!("{:?}", prop);
+                        }
                     }
                     if sql.len() > 1 {
                         println!("Results for query '{}': \n", sqlquery);
                     }
                     println!("{}", table.render());
-                } else {
-                    println!("No tables are configured in your config file.");
-                    println!("See example config file below.\n");
-                    println!("{}", CONFIG_FILE_EXAMPLE);
                 }
-            } else if query.var.to_uppercase() == "DATABASES" {
-                let mut table = Table::new();
-                table.style = TableStyle::simple();
-                let mut row = Vec::new();
-                row.push(TableCell::new_with_alignment(
-                    "Database name",
-                    1,
-                    Alignment::Left,
-                ));
-                row.push(TableCell::new_with_alignment(
-                    "Configured server",
-                    1,
-                    Alignment::Left,
-                ));
-                table.add_row(Row::new(row));
-
-                for (sec, prop) in &conf {
-                    if sec.is_some()
-                        && sec.unwrap().find(".tables").is_none()
-                        && sec.unwrap().find(".queries").is_none()
-                    {
-                        let mut row = Vec::new();
-                        row.push(TableCell::new_with_alignment(
-                            sec.unwrap(),
-                            1,
-                            Alignment::Left,
-                        ));
-                        for (key, value) in prop.iter() {
-                            if key == "connection" {
-                                row.push(TableCell::new_with_alignment(value, 1, Alignment::Left));
-                            }
-                        }
-                        table.add_row(Row::new(row));
-                        //println!("{:?}", prop);
-                    }
+            } else if query.qtype == QueryType::SELECT {
+                let mut headers: Vec<String> =
+                    query.columns.iter().map(|(name, _)| name.clone()).collect();
+                if federated {
+                    headers.insert(0, "server".to_string());
                 }
+                let identifier_len = headers.len() as usize;
+                let one_table = identifier_len == 1;
                 if sql.len() > 1 {
                     println!("Results for query '{}': \n", sqlquery);
                 }
-                println!("{}", table.render());
-            }
-        } else if query.qtype == QueryType::SELECT {
-            let (rs, _res) = ldap
-                .search(
-                    &query.table,
-                    Scope::Subtree,
-                    &query.filter,
-                    query.identifiers.clone(),
-                )?
-                .success()
-                .context("Ldap search failed")?;
-
-            let identifier_len = query.identifiers.len() as usize;
-            let one_table = identifier_len == 1;
-
-            if sql.len() > 1 {
-                println!("Results for query '{}': \n", sqlquery);
-            }
-
-            // if we requested only one field, only show one table
-            let result = if !one_table || query.wildcard {
-                let mut result = Vec::new();
-                for entry in rs {
-                    let se = SearchEntry::construct(entry);
 
-                    let mut keyval = HashMap::new();
-                    for a in se.attrs {
-                        let (key, value) = a;
-                        keyval.insert(key, value.join("\n"));
-                    }
-                    result.push(keyval);
+                if servers.session.output == Output::Csv {
+                    print_csv(&headers, &records, query.wildcard);
+                    print_referrals(&mut servers);
+                    continue;
                 }
-                result
-            } else {
-                let mut result = Vec::new();
-                let mut key = String::from("");
-                for entry in rs {
-                    let se = SearchEntry::construct(entry);
 
-                    for a in se.attrs {
-                        let (key_attr, val_attr) = a;
-                        key = key_attr;
-                        result.push(val_attr.join("\n"));
+                // if we requested only one field, only show one table
+                let result = if !one_table || query.wildcard {
+                    let mut result = Vec::new();
+                    for record in records {
+                        let mut keyval = HashMap::new();
+                        for (key, value) in record.attrs {
+                            keyval.insert(key, value.join("\n"));
+                        }
+                        result.push(keyval);
                     }
-                }
-                let mut keyval = HashMap::new();
-                keyval.insert(key, result.join("\n"));
-                let mut result = Vec::new();
-                result.push(keyval);
-
-                result
-            };
-
-            if binddn.is_some() && bindpassword.is_some() {
-                ldap.unbind().context("ldap unbind failed")?;
-            }
-
-            // the reason we stored everything in a vec is because
-            // ldap3 doesn't return the requested fields in the same
-            // order they where requested, eg: cn,uid,passwordretrycount
-            // every new run they are resulted in a different order
-            // this fixes this problem
-
-            let mut table = Table::new();
-            table.style = TableStyle::simple();
+                    result
+                } else {
+                    let mut result = Vec::new();
+                    for record in records {
+                        for value in record.attrs.values() {
+                            if !value.is_empty() {
+                                result.push(value.join("\n"));
+                            }
+                        }
+                    }
+                    let mut keyval = HashMap::new();
+                    keyval.insert(headers[0].clone(), result.join("\n"));
+                    vec![keyval]
+                };
 
-            if !query.wildcard {
-                let mut row = Vec::new();
+                // the reason we stored everything in a vec is because
+                // ldap3 doesn't return the requested fields in the same
+                // order they where requested, eg: cn,uid,passwordretrycount
+                // every new run they are resulted in a different order
+                // this fixes this problem
 
-                // print header
-                for key in &query.identifiers {
-                    row.push(TableCell::new_with_alignment(key, 1, Alignment::Left));
-                }
-                if !row.is_empty() {
-                    table.add_row(Row::new(row));
-                }
+                let mut table = Table::new();
+                table.style = TableStyle::simple();
 
-                // print data
-                for result_entry in result.iter() {
+                if !query.wildcard {
                     let mut row = Vec::new();
 
-                    for key in &query.identifiers {
-                        let val = result_entry.get(key);
-                        if let Some(value) = val {
-                            row.push(TableCell::new_with_alignment(value, 1, Alignment::Left));
-                        } else {
-                            row.push(TableCell::new_with_alignment("", 1, Alignment::Left));
-                        }
+                    // print header
+                    for key in &headers {
+                        row.push(TableCell::new_with_alignment(key, 1, Alignment::Left));
                     }
                     if !row.is_empty() {
                         table.add_row(Row::new(row));
                     }
-                }
 
-                let table_data = table.render();
-
-                if !fits_on_screen(&table_data) {
+                    // print data
                     for result_entry in result.iter() {
-                        if !one_table {
-                            table = Table::new();
-                            table.style = TableStyle::simple();
-                        }
-                        for key in &query.identifiers {
-                            let val = result_entry.get(&key.to_string());
+                        let mut row = Vec::new();
+
+                        for key in &headers {
+                            let val = result_entry.get(key);
                             if let Some(value) = val {
-                                let mut row = Vec::new();
-                                row.push(TableCell::new_with_alignment(key, 1, Alignment::Left));
                                 row.push(TableCell::new_with_alignment(value, 1, Alignment::Left));
-                                table.add_row(Row::new(row));
+                            } else {
+                                row.push(TableCell::new_with_alignment("", 1, Alignment::Left));
+                            }
+                        }
+                        if !row.is_empty() {
+                            table.add_row(Row::new(row));
+                        }
+                    }
+
+                    let table_data = table.render();
+
+                    if !fits_on_screen(&table_data) {
+                        for result_entry in result.iter() {
+                            if !one_table {
+                                table = Table::new();
+                                table.style = TableStyle::simple();
+                            }
+                            for key in &headers {
+                                let val = result_entry.get(&key.to_string());
+                                if let Some(value) = val {
+                                    table.add_row(Row::new(vec![
+                                        TableCell::new_with_alignment(key, 1, Alignment::Left),
+                                        TableCell::new_with_alignment(value, 1, Alignment::Left),
+                                    ]));
+                                }
+                            }
+                            if !one_table {
+                                if !table.rows.is_empty() {
+                                    println!("{}", table.render());
+                                } else {
+                                    println!("Nothing found.");
+                                }
                             }
                         }
-                        if !one_table {
+                        if one_table {
                             if !table.rows.is_empty() {
                                 println!("{}", table.render());
                             } else {
                                 println!("Nothing found.");
                             }
                         }
-                    }
-                    if one_table {
+                    } else {
                         if !table.rows.is_empty() {
-                            println!("{}", table.render());
+                            println!("{}", table_data);
                         } else {
                             println!("Nothing found.");
                         }
                     }
                 } else {
-                    if !table.rows.is_empty() {
-                        println!("{}", table_data);
-                    } else {
-                        println!("Nothing found.");
-                    }
-                }
-            } else {
-                let mut keys = Vec::new();
+                    let mut keys = Vec::new();
 
-                for result_entry in result.iter() {
-                    //for (key, _val) in result_entry {
-                    for key in result_entry.keys() {
-                        keys.push(key.to_string());
+                    for result_entry in result.iter() {
+                        //for (key, _val) in result_entry {
+                        for key in result_entry.keys() {
+                            keys.push(key.to_string());
+                        }
                     }
-                }
-
-                keys.sort(); // sort the rows
-                keys.dedup(); // remove duplicates
 
-                let mut row = Vec::new();
-                for key in keys.iter() {
-                    row.push(TableCell::new_with_alignment(key, 1, Alignment::Left));
-                }
-                if !row.is_empty() {
-                    table.add_row(Row::new(row));
-                }
+                    keys.sort(); // sort the rows
+                    keys.dedup(); // remove duplicates
 
-                for result_entry in result.iter() {
                     let mut row = Vec::new();
                     for key in keys.iter() {
-                        let val = result_entry.get(&key.to_string());
-                        if let Some(value) = val {
-                            row.push(TableCell::new_with_alignment(value, 1, Alignment::Left));
-                        } else {
-                            row.push(TableCell::new_with_alignment("", 1, Alignment::Left));
-                        }
+                        row.push(TableCell::new_with_alignment(key, 1, Alignment::Left));
                     }
                     if !row.is_empty() {
                         table.add_row(Row::new(row));
                     }
-                }
 
-                let table_data = table.render();
-
-                if !fits_on_screen(&table_data) {
-                    for result_entry in result {
-                        if !one_table {
-                            table = Table::new();
-                            table.style = TableStyle::simple();
-                        }
+                    for result_entry in result.iter() {
+                        let mut row = Vec::new();
                         for key in keys.iter() {
                             let val = result_entry.get(&key.to_string());
                             if let Some(value) = val {
-                                let mut row = Vec::new();
-                                row.push(TableCell::new_with_alignment(key, 1, Alignment::Left));
                                 row.push(TableCell::new_with_alignment(value, 1, Alignment::Left));
-                                table.add_row(Row::new(row));
+                            } else {
+                                row.push(TableCell::new_with_alignment("", 1, Alignment::Left));
+                            }
+                        }
+                        if !row.is_empty() {
+                            table.add_row(Row::new(row));
+                        }
+                    }
+
+                    let table_data = table.render();
+
+                    if !fits_on_screen(&table_data) {
+                        for result_entry in result {
+                            if !one_table {
+                                table = Table::new();
+                                table.style = TableStyle::simple();
+                            }
+                            for key in keys.iter() {
+                                let val = result_entry.get(&key.to_string());
+                                if let Some(value) = val {
+                                    table.add_row(Row::new(vec![
+                                        TableCell::new_with_alignment(key, 1, Alignment::Left),
+                                        TableCell::new_with_alignment(value, 1, Alignment::Left),
+                                    ]));
+                                }
+                            }
+                            if !one_table {
+                                if !table.rows.is_empty() {
+                                    println!("{}", table.render());
+                                    println!("\nConsider replacing * with a subset of the folling fields: \n");
+                                    println!("{}", keys.join(","));
+                                } else {
+                                    println!("Nothing found.");
+                                }
                             }
                         }
-                        if !one_table {
+                        if one_table {
                             if !table.rows.is_empty() {
                                 println!("{}", table.render());
-                                println!("\nConsider replacing * with a subset of the folling fields: \n");
+                                println!(
+                                "\nConsider replacing * with a subset of the folling fields: \n"
+                            );
                                 println!("{}", keys.join(","));
                             } else {
                                 println!("Nothing found.");
                             }
                         }
-                    }
-                    if one_table {
+                    } else {
                         if !table.rows.is_empty() {
-                            println!("{}", table.render());
-                            println!(
-                                "\nConsider replacing * with a subset of the folling fields: \n"
-                            );
-                            println!("{}", keys.join(","));
+                            println!("{}", table_data);
                         } else {
                             println!("Nothing found.");
                         }
                     }
-                } else {
-                    if !table.rows.is_empty() {
-                        println!("{}", table_data);
-                    } else {
-                        println!("Nothing found.");
-                    }
                 }
             }
+
+            print_referrals(&mut servers);
+        }
+        Ok(())
+    };
+    let result = run();
+    if let Some(transaction) = transaction.take() {
+        match &result {
+            Ok(_) => println!("The statements ended without COMMIT."),
+            Err(e) => println!("Error: {:#}", e),
         }
+        transaction.rollback(&mut servers)?;
     }
+    result?;
+    servers.unbind()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_split_on_separators_only() {
+        let sql = "SET PASSWORD FOR 'uid=jdoe' = 'a;b';\nUPDATE @people SET description = 'x;''y' WHERE uid=jdoe;\n-- done; really\nSELECT \"a;b\" FROM @people";
+        assert_eq!(
+            statements(sql),
+            vec![
+                "SET PASSWORD FOR 'uid=jdoe' = 'a;b'",
+                "UPDATE @people SET description = 'x;''y' WHERE uid=jdoe",
+                "-- done; really\nSELECT \"a;b\" FROM @people",
+            ]
+        );
+        assert_eq!(statements("@testquery;"), vec!["@testquery", ""]);
+    }
+}
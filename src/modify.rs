@@ -0,0 +1,398 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Writing to the directory.
+//!
+//! An UPDATE first searches the entries its WHERE clause matches, the
+//! changes are then applied one entry at a time with a modify operation.
+//! An INSERT adds an entry per row of its VALUES, a DELETE removes the
+//! entries it found one by one. `SET dn`, `SET rdn` and `ALTER ENTRY ...
+//! MOVE TO` rename or move entries with a modify dn operation. Passwords
+//! are set the way the server wants them, see password().
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use ldap3::controls::RawControl;
+use ldap3::exop::PasswordModify;
+use ldap3::result::ExopResult;
+use ldap3::{LdapConn, LdapResult, Mod};
+use sqlparser::ast::{BinaryOperator, Expr, Function, Ident, Value};
+
+use crate::dn;
+use crate::eval;
+use crate::ldif;
+use crate::params;
+use crate::server::{Flavour, Servers};
+use crate::transaction::TRANSACTION_SPECIFICATION_OID;
+
+// the tree delete control (draft-armijo-ldap-treedelete), supported by
+// Active Directory and OpenLDAP
+pub const TREE_DELETE_OID: &str = "1.2.840.113556.1.4.805";
+
+// notAllowedOnNonLeaf
+const NOT_ALLOWED_ON_NON_LEAF: u32 = 66;
+
+// unwillingToPerform, eg: Active Directory for a password sent unencrypted
+const UNWILLING_TO_PERFORM: u32 = 53;
+
+// LDAP_X_TXN_SPECIFY_OKAY, the result of OpenLDAP for a change that was
+// added to a transaction
+const TRANSACTION_SPECIFY_OKAY: u32 = 0x4120;
+
+/// A change of a single entry.
+#[derive(Clone)]
+pub struct Change {
+    pub dn: String,
+    pub operation: Operation,
+}
+
+#[derive(Clone)]
+pub enum Operation {
+    Modify(Vec<Mod<String>>),
+    Add(Vec<(String, HashSet<String>)>),
+    // with subtree, the entries below the entry are deleted as well
+    Delete {
+        subtree: bool,
+    },
+    // the new rdn and, when the entry moves, its new parent
+    ModifyDn {
+        rdn: String,
+        delete_old: bool,
+        new_superior: Option<String>,
+    },
+    // the new password
+    Password(String),
+}
+
+impl Operation {
+    // how the result of the operation is reported: "added: dn"
+    fn verb(&self) -> &'static str {
+        match self {
+            Operation::Modify(_) => "modified",
+            Operation::Add(_) => "added",
+            Operation::Delete { .. } => "deleted",
+            Operation::ModifyDn {
+                new_superior: Some(_),
+                ..
+            } => "moved",
+            Operation::ModifyDn { .. } => "renamed",
+            Operation::Password(_) => "password set",
+        }
+    }
+}
+
+/// The modification of `SET attr = ...`:
+///
+/// * `attr = 'x'` replaces all values by x, `attr = ARRAY('x', 'y')` by x and y
+/// * `attr = attr + 'x'` or `attr = ARRAY_APPEND(attr, 'x')` adds the value x
+/// * `attr = attr - 'x'` or `attr = ARRAY_REMOVE(attr, 'x')` removes the value x
+/// * `attr = NULL` removes the attribute
+pub fn assignment(attr: &str, value: &Expr) -> Result<Mod<String>> {
+    let attr = attr.to_string();
+    match value {
+        Expr::Value(Value::Null) => Ok(Mod::Delete(attr, HashSet::new())),
+        Expr::BinaryOp {
+            op: BinaryOperator::Plus,
+            ..
+        } => Ok(Mod::Add(
+            attr.clone(),
+            operands(&attr, value, &BinaryOperator::Plus)?,
+        )),
+        Expr::BinaryOp {
+            op: BinaryOperator::Minus,
+            ..
+        } => Ok(Mod::Delete(
+            attr.clone(),
+            operands(&attr, value, &BinaryOperator::Minus)?,
+        )),
+        Expr::Function(f) => {
+            let name = f.name.to_string().to_uppercase();
+            let (first, rest) = match f.args.split_first() {
+                Some(args) => args,
+                None => bail!("{}() needs at least one value", name),
+            };
+            let is_attr = matches!(first, Expr::Identifier(i) if is_attr(i, &attr));
+            let values = |args: &[Expr]| args.iter().map(literal).collect::<Result<HashSet<_>>>();
+            match name.as_str() {
+                "ARRAY" => Ok(Mod::Replace(attr, values(&f.args)?)),
+                "ARRAY_APPEND" if is_attr && !rest.is_empty() => Ok(Mod::Add(attr, values(rest)?)),
+                "ARRAY_REMOVE" if is_attr && !rest.is_empty() => {
+                    Ok(Mod::Delete(attr, values(rest)?))
+                }
+                "ARRAY_APPEND" | "ARRAY_REMOVE" => bail!(
+                    "Use {}({}, 'value', ...) to add or remove values of {}",
+                    name,
+                    attr,
+                    attr
+                ),
+                _ => bail!(
+                    "Unsupported function {}, use ARRAY, ARRAY_APPEND or ARRAY_REMOVE",
+                    name
+                ),
+            }
+        }
+        _ => {
+            let mut values = HashSet::new();
+            values.insert(literal(value)?);
+            Ok(Mod::Replace(attr, values))
+        }
+    }
+}
+
+// Whether ident is the attribute itself, as in mail = mail + 'x'.
+fn is_attr(ident: &Ident, attr: &str) -> bool {
+    ident.value.eq_ignore_ascii_case(attr) && !params::is_value(ident)
+}
+
+// The values of attr + 'x' + 'y' or attr - 'x' - 'y'.
+fn operands(attr: &str, expr: &Expr, op: &BinaryOperator) -> Result<HashSet<String>> {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: operator,
+            right,
+        } if operator == op => {
+            let mut values = operands(attr, left, op)?;
+            values.insert(literal(right)?);
+            Ok(values)
+        }
+        Expr::Identifier(ident) if is_attr(ident, attr) => Ok(HashSet::new()),
+        _ => bail!(
+            "Use {} = {} {} 'value' to add or remove a value of {}",
+            attr,
+            attr,
+            op,
+            attr
+        ),
+    }
+}
+
+/// The values of an INSERT column: a single value or ARRAY('x', 'y'). NULL
+/// leaves the attribute out.
+pub fn values(expr: &Expr) -> Result<HashSet<String>> {
+    match expr {
+        Expr::Value(Value::Null) => Ok(HashSet::new()),
+        Expr::Function(f) if f.name.to_string().eq_ignore_ascii_case("ARRAY") => {
+            f.args.iter().map(literal).collect()
+        }
+        Expr::Function(f) => bail!("Unsupported function {}, use ARRAY('x', 'y')", f.name),
+        _ => {
+            let mut values = HashSet::new();
+            values.insert(literal(expr)?);
+            Ok(values)
+        }
+    }
+}
+
+/// The password of `SET userPassword = PASSWORD('secret')` and `SET
+/// PASSWORD FOR 'dn' = PASSWORD('secret')`.
+pub fn password(attr: &str, f: &Function) -> Result<String> {
+    if !attr.eq_ignore_ascii_case("userPassword") && !attr.eq_ignore_ascii_case("unicodePwd") {
+        bail!(
+            "PASSWORD() sets the password, use SET userPassword = PASSWORD('...') instead of {}",
+            attr
+        );
+    }
+    match f.args.as_slice() {
+        [password] => literal(password),
+        _ => bail!("Use PASSWORD('new password')"),
+    }
+}
+
+/// Renames the entry dn to new_dn, which moves it when the parent of new_dn
+/// is another entry. The old rdn value is removed from the entry.
+pub fn rename(dn: &str, new_dn: &str) -> Result<Operation> {
+    let rdns = dn::parse(new_dn).context(format!("Wrong dn {}", new_dn))?;
+    let rdn = match rdns.first() {
+        Some(rdn) => dn::rdn_to_string(rdn),
+        None => bail!("The new dn of {} is empty", dn),
+    };
+    let new_superior = if rdns.len() > 1 {
+        let parent = dn::to_string(&rdns[1..]);
+        if dn::normalize(&parent)? == dn::normalize(&dn::parent(dn, 1)?)? {
+            None
+        } else {
+            Some(parent)
+        }
+    } else {
+        None
+    };
+    Ok(Operation::ModifyDn {
+        rdn,
+        delete_old: true,
+        new_superior,
+    })
+}
+
+/// Moves the entry dn below the entry new_superior, keeping its rdn.
+pub fn move_to(dn: &str, new_superior: &str) -> Result<Operation> {
+    dn::parse(new_superior).context(format!("Wrong dn {}", new_superior))?;
+    Ok(Operation::ModifyDn {
+        rdn: dn::rdn(dn)?,
+        delete_old: true,
+        new_superior: Some(new_superior.to_string()),
+    })
+}
+
+/// A single value to write, a string, number or word.
+pub fn literal(expr: &Expr) -> Result<String> {
+    match expr {
+        Expr::Value(Value::Null) => {
+            bail!("NULL can only be used as SET attr = NULL, which removes the attribute")
+        }
+        Expr::Value(value) => Ok(eval::literal(value).unwrap_or_default()),
+        Expr::Identifier(ident) => Ok(ident.value.clone()),
+        _ => bail!("Only Value and Identifier are supported."),
+    }
+}
+
+// Active Directory wants the password in quotes, encoded as UTF-16LE, in
+// unicodePwd. Other servers hash the password of a Password Modify
+// operation (RFC 3062), a userPassword that is replaced is stored as it is.
+fn set_password(
+    ldap: &mut LdapConn,
+    flavour: Flavour,
+    dn: &str,
+    password: &str,
+) -> ldap3::result::Result<LdapResult> {
+    if flavour == Flavour::ActiveDirectory {
+        let quoted: Vec<u8> = format!("\"{}\"", password)
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes().to_vec())
+            .collect();
+        let mut values = HashSet::new();
+        values.insert(quoted);
+        return ldap.modify(dn, vec![Mod::Replace(b"unicodePwd".to_vec(), values)]);
+    }
+    ldap.extended(PasswordModify {
+        user_id: Some(dn),
+        old_pass: None,
+        new_pass: Some(password),
+    })
+    .map(|ExopResult(_, result)| result)
+}
+
+/// Shows the changes as LDIF instead of making them.
+pub fn explain(changes: &[Change]) {
+    if changes.is_empty() {
+        println!("No entries would be changed.\n");
+        return;
+    }
+    println!(
+        "{} changes, nothing is sent to the server:\n",
+        changes.len()
+    );
+    for change in changes {
+        print!("{}", ldif::change_record(change));
+    }
+}
+
+/// Applies the changes on a server. An entry that can't be changed
+/// doesn't stop the others, the result of every entry is printed followed
+/// by a count. Returns whether each change was made.
+pub fn apply(servers: &mut Servers, server: &str, changes: Vec<Change>) -> Result<Vec<bool>> {
+    let mut results = Vec::new();
+    if changes.is_empty() {
+        println!("No entries to change.\n");
+        return Ok(results);
+    }
+    // the changes are part of the transaction of BEGIN
+    let transaction: Vec<RawControl> = servers
+        .session
+        .transaction
+        .iter()
+        .map(|id| RawControl {
+            ctype: TRANSACTION_SPECIFICATION_OID.to_string(),
+            crit: true,
+            val: Some(id.clone()),
+        })
+        .collect();
+    let ok = |rc: u32| rc == 0 || (rc == TRANSACTION_SPECIFY_OKAY && !transaction.is_empty());
+    let passwords = changes
+        .iter()
+        .any(|c| matches!(c.operation, Operation::Password(_)));
+    let flavour = if passwords {
+        servers.flavour(server)?
+    } else {
+        Flavour::Other
+    };
+    let ldap = servers.ldap(server)?;
+    // the number of entries per verb, in the order they were first seen
+    let mut done: Vec<(&str, usize)> = Vec::new();
+    let mut failed = 0;
+    for change in changes {
+        let verb = change.operation.verb();
+        let password = matches!(change.operation, Operation::Password(_));
+        let mut controls = transaction.clone();
+        if let Operation::Delete { subtree: true } = change.operation {
+            controls.push(RawControl {
+                ctype: TREE_DELETE_OID.to_string(),
+                crit: true,
+                val: None,
+            });
+        }
+        // the Password Modify operation can't be part of a transaction
+        if !controls.is_empty() && !matches!(change.operation, Operation::Password(_)) {
+            ldap.with_controls(controls);
+        }
+        let result = match change.operation {
+            Operation::Modify(mods) => ldap.modify(&change.dn, mods),
+            Operation::Add(attrs) => ldap.add(&change.dn, attrs),
+            Operation::Delete { .. } => ldap.delete(&change.dn),
+            Operation::ModifyDn {
+                rdn,
+                delete_old,
+                new_superior,
+            } => ldap.modifydn(&change.dn, &rdn, delete_old, new_superior.as_deref()),
+            Operation::Password(password) => set_password(ldap, flavour, &change.dn, &password),
+        };
+        results.push(matches!(&result, Ok(result) if ok(result.rc)));
+        match result {
+            Ok(result) if ok(result.rc) => {
+                println!("{}: {}", verb, change.dn);
+                match done.iter_mut().find(|(v, _)| *v == verb) {
+                    Some((_, count)) => *count += 1,
+                    None => done.push((verb, 1)),
+                }
+            }
+            Ok(result) => {
+                println!("failed: {}: {}", change.dn, result);
+                if result.rc == NOT_ALLOWED_ON_NON_LEAF {
+                    println!(
+                        "  the entry has entries below it, SET subtreedelete = ON deletes them too"
+                    );
+                }
+                if result.rc == UNWILLING_TO_PERFORM
+                    && password
+                    && flavour == Flavour::ActiveDirectory
+                {
+                    println!("  Active Directory only accepts passwords over ldaps:// or StartTLS");
+                }
+                failed += 1;
+            }
+            Err(e) => {
+                println!("failed: {}: {}", change.dn, e);
+                failed += 1;
+            }
+        }
+    }
+    let mut summary: Vec<String> = done
+        .iter()
+        .map(|(verb, count)| format!("{} entries {}", count, verb))
+        .collect();
+    if summary.is_empty() {
+        summary.push(String::from("No entries changed"));
+    }
+    println!("\n{}, {} failed.\n", summary.join(", "), failed);
+    Ok(results)
+}
@@ -0,0 +1,126 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Placeholders in queries: `SELECT cn FROM @group WHERE memberuid=:user`
+//! run as `sqldap @testquery user=jdoe` or `sqldap @testquery jdoe`.
+//!
+//! The tokens of a placeholder are replaced by its value as a word quoted
+//! with `'`. The sql itself can't have such a word (quoted names use `"`),
+//! so a value stays apart from the strings of the query: sqldap escapes it
+//! in the search filter and compares it exactly, `*` or `)` in a value are
+//! matched literally and can't change the filter.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use sqlparser::ast::Ident;
+use sqlparser::dialect::GenericDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+
+#[derive(Default)]
+pub struct Parameters {
+    // user=jdoe
+    pub named: HashMap<String, String>,
+    // the other values, they are given to the placeholders that have no
+    // named value in the order the placeholders appear
+    pub positional: Vec<String>,
+}
+
+impl Parameters {
+    /// Splits `name=value` into its name and value, None when the argument
+    /// isn't of that form.
+    pub fn assignment(arg: &str) -> Option<(String, String)> {
+        let pos = arg.find('=')?;
+        let name = &arg[..pos];
+        if is_name(name) {
+            Some((name.to_string(), arg[pos + 1..].to_string()))
+        } else {
+            None
+        }
+    }
+
+    /// Finds a value for every placeholder in sql, afterwards `named` has
+    /// the values of all of them.
+    pub fn bind(&mut self, sql: &str) -> Result<()> {
+        let tokens = match Tokenizer::new(&GenericDialect {}, sql).tokenize() {
+            Ok(tokens) => tokens,
+            // the parser reports the error
+            Err(_) => return Ok(()),
+        };
+        let mut previous = None;
+        for token in &tokens {
+            if let Some(name) = placeholder(previous, token) {
+                self.value(name)?;
+            }
+            previous = Some(token);
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, name: &str) -> Result<String> {
+        if let Some(value) = self.named.get(name) {
+            return Ok(value.to_string());
+        }
+        if self.positional.is_empty() {
+            bail!(
+                "No value for :{}, add {}=... to the command line",
+                name,
+                name
+            );
+        }
+        let value = self.positional.remove(0);
+        self.named.insert(name.to_string(), value.clone());
+        Ok(value)
+    }
+}
+
+/// Replaces the placeholders in tokens by their values.
+pub fn substitute(tokens: Vec<Token>, values: &HashMap<String, String>) -> Result<Vec<Token>> {
+    let mut result: Vec<Token> = Vec::new();
+    for token in tokens {
+        if let Some(name) = placeholder(result.last(), &token) {
+            let value = match values.get(name) {
+                Some(value) => value,
+                None => bail!("No value for :{}", name),
+            };
+            result.pop();
+            result.push(Token::make_word(value, Some('\'')));
+            continue;
+        }
+        result.push(token);
+    }
+    Ok(result)
+}
+
+/// Returns true when ident is the value of a placeholder.
+pub fn is_value(ident: &Ident) -> bool {
+    ident.quote_style == Some('\'')
+}
+
+// The name of :name when token is the word after the colon.
+fn placeholder<'a>(previous: Option<&Token>, token: &'a Token) -> Option<&'a str> {
+    match (previous, token) {
+        (Some(Token::Colon), Token::Word(w)) if w.quote_style.is_none() && is_name(&w.value) => {
+            Some(&w.value)
+        }
+        _ => None,
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,614 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Execution of SELECT queries: the searches, joins between tables and
+//! the client side part of WHERE, GROUP BY and the SELECT list.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use ldap3::{
+    parse_refs, DerefAliases, LdapConn, LdapError, Scope, SearchEntry, SearchOptions, SearchResult,
+};
+use sqlparser::ast::{BinaryOperator, Expr, SetOperator};
+
+use crate::dn;
+use crate::eval;
+use crate::eval::Record;
+use crate::server::Servers;
+use crate::{escape_filter_value, JoinedTable, SqldapQuery, FILTER_BATCH_SIZE};
+
+/// What happens with the referrals a search returns: they are left out,
+/// listed with the results or followed with the credentials of the server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Referrals {
+    Ignore,
+    Report,
+    Follow,
+}
+
+/// The entries of one or more searches. `limited` has a message for every
+/// search that a time or size limit stopped before it found all entries.
+#[derive(Clone, Default)]
+pub struct Found {
+    pub records: Vec<Record>,
+    pub limited: Vec<String>,
+}
+
+impl Found {
+    /// The records when none of the searches was cut short. The entries a
+    /// change or a subquery works on have to be complete: NOT IN a
+    /// truncated list matches entries it should leave out.
+    pub fn complete(self) -> Result<Vec<Record>> {
+        if let Some(message) = self.limited.first() {
+            bail!("{} Narrow the WHERE clause or raise the limit.", message);
+        }
+        Ok(self.records)
+    }
+
+    fn append(&mut self, mut other: Found) {
+        self.records.append(&mut other.records);
+        self.limited.append(&mut other.limited);
+    }
+}
+
+/// Where one search runs and how.
+pub struct Target<'a> {
+    pub server: &'a str,
+    pub base: &'a str,
+    pub scope: Scope,
+    pub deref: DerefAliases,
+    pub referrals: Referrals,
+}
+
+pub fn search(
+    servers: &mut Servers,
+    target: &Target,
+    filter: &str,
+    attrs: Vec<String>,
+) -> Result<Found> {
+    let (sizelimit, timelimit) = servers.limits(target.server)?;
+    let options = SearchOptions::new()
+        .deref(target.deref)
+        .sizelimit(sizelimit)
+        .timelimit(timelimit);
+    let ldap = servers.ldap(target.server)?;
+    let (mut found, refs) = search_conn(
+        ldap,
+        target.base,
+        target.scope,
+        options,
+        filter,
+        attrs.clone(),
+    )?;
+
+    match target.referrals {
+        Referrals::Ignore => {}
+        Referrals::Report => servers.referrals.extend(refs),
+        Referrals::Follow => {
+            for url in refs {
+                match follow(servers, target, &url, filter, attrs.clone()) {
+                    Ok(more) => found.append(more),
+                    Err(e) => {
+                        println!("Cannot follow referral {}: {:#}", url, e);
+                        servers.referrals.push(url);
+                    }
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+// Returns the entries and the referrals of a search.
+fn search_conn(
+    ldap: &mut LdapConn,
+    base: &str,
+    scope: Scope,
+    options: SearchOptions,
+    filter: &str,
+    attrs: Vec<String>,
+) -> Result<(Found, Vec<String>)> {
+    let SearchResult(entries, res) = ldap
+        .with_search_options(options.clone())
+        .search(base, scope, filter, attrs)?;
+
+    let mut refs = Vec::new();
+    let mut limited = Vec::new();
+    match res.rc {
+        // the base is not on this server, the result refers to the one it is on
+        10 => refs.extend(res.refs),
+        // the entries found before the limit was hit are returned
+        3 => limited.push(format!(
+            "Time limit exceeded: the search of {} was stopped after {}, the results are incomplete.",
+            base,
+            if options.timelimit > 0 {
+                format!("{} seconds", options.timelimit)
+            } else {
+                "the time limit of the server".to_string()
+            }
+        )),
+        4 => limited.push(format!(
+            "Size limit exceeded: the search of {} returned only the first {} entries.",
+            base,
+            entries.iter().filter(|e| !e.is_ref()).count()
+        )),
+        _ => {
+            res.success().context("Ldap search failed")?;
+        }
+    }
+
+    let mut records = Vec::new();
+    for entry in entries {
+        if entry.is_ref() {
+            refs.extend(parse_refs(entry.0));
+        } else {
+            records.push(Record::new(SearchEntry::construct(entry)));
+        }
+    }
+    Ok((Found { records, limited }, refs))
+}
+
+// Runs the search again on the server a referral points to. Referrals
+// returned by that server are listed, not followed.
+fn follow(
+    servers: &mut Servers,
+    target: &Target,
+    url: &str,
+    filter: &str,
+    attrs: Vec<String>,
+) -> Result<Found> {
+    let (sizelimit, timelimit) = servers.limits(target.server)?;
+    let options = SearchOptions::new()
+        .deref(target.deref)
+        .sizelimit(sizelimit)
+        .timelimit(timelimit);
+    let (connection, base, scope) = parse_referral(url)?;
+    let base = if base.is_empty() {
+        target.base.to_string()
+    } else {
+        base
+    };
+    let ldap = servers.referral(target.server, &connection)?;
+    let (found, refs) = search_conn(
+        ldap,
+        &base,
+        scope.unwrap_or(target.scope),
+        options,
+        filter,
+        attrs,
+    )?;
+    servers.referrals.extend(refs);
+    Ok(found)
+}
+
+// Splits ldap://host:389/ou=people,dc=example,dc=com??one into the url of
+// the server, the base and the scope.
+fn parse_referral(url: &str) -> Result<(String, String, Option<Scope>)> {
+    let start = match url.find("://") {
+        Some(pos) => pos + 3,
+        None => bail!("Referral {} is not an ldap url", url),
+    };
+    let (connection, rest) = match url[start..].find('/') {
+        Some(pos) => (&url[..start + pos], &url[start + pos + 1..]),
+        None => (url, ""),
+    };
+    let mut parts = rest.split('?');
+    let base = percent_decode(parts.next().unwrap_or(""))?;
+    let scope = match parts.nth(1) {
+        Some("base") => Some(Scope::Base),
+        Some("one") => Some(Scope::OneLevel),
+        Some("sub") => Some(Scope::Subtree),
+        _ => None,
+    };
+    Ok((connection.to_string(), base, scope))
+}
+
+fn percent_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3])?;
+            decoded.push(u8::from_str_radix(hex, 16)?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+/// Runs a SELECT query and returns one record per result row, holding the
+/// selected columns.
+pub fn run(servers: &mut Servers, query: &SqldapQuery) -> Result<Found> {
+    if let Some(set) = &query.set_operation {
+        let mut left = run(servers, &set.left)?;
+        let right = run(servers, &set.right)?;
+        // a joined row holds several entries, its dn is only that of the
+        // first table
+        let by_values = joined(&set.left) || joined(&set.right);
+        left.limited.extend(right.limited);
+        return Ok(Found {
+            records: combine(&set.op, set.all, left.records, right.records, by_values),
+            limited: left.limited,
+        });
+    }
+
+    let filter = if query.filter.is_empty() {
+        "(objectClass=*)"
+    } else {
+        &query.filter
+    };
+    let target = Target {
+        server: &query.server,
+        base: &query.table,
+        scope: query.scope,
+        deref: query.deref,
+        referrals: query.referrals,
+    };
+    let Found {
+        mut records,
+        mut limited,
+    } = if let Some(rows) = &query.rows {
+        rows.clone()
+    } else if query.chunks.is_empty() {
+        search(servers, &target, filter, query.identifiers.clone())?
+    } else {
+        let mut result = Found::default();
+        let mut seen = HashSet::new();
+        for filter in &query.chunks {
+            let found = search(servers, &target, filter, query.identifiers.clone())?;
+            for record in found.records {
+                if seen.insert(record.dn.clone()) {
+                    result.records.push(record);
+                }
+            }
+            result.limited.extend(found.limited);
+        }
+        result
+    };
+
+    if !query.joins.is_empty() {
+        records = records
+            .into_iter()
+            .map(|record| record.qualify(&query.alias))
+            .collect();
+        for table in &query.joins {
+            let found = join(servers, records, table)?;
+            records = found.records;
+            limited.extend(found.limited);
+        }
+    }
+
+    if let Some(selection) = &query.selection {
+        let mut selected = Vec::new();
+        for record in records {
+            if eval::matches(selection, &record)? {
+                selected.push(record);
+            }
+        }
+        records = selected;
+    }
+
+    Ok(Found {
+        records: project(query, records)?,
+        limited,
+    })
+}
+
+// noSuchObject: the entry doesn't exist.
+fn is_no_such_object(e: &anyhow::Error) -> bool {
+    e.chain().any(|c| {
+        matches!(c.downcast_ref::<LdapError>(), Some(LdapError::LdapResult { result }) if result.rc == 32)
+    })
+}
+
+// Whether the rows of a query combine entries of several tables.
+fn joined(query: &SqldapQuery) -> bool {
+    match &query.set_operation {
+        Some(set) => joined(&set.left) || joined(&set.right),
+        None => !query.joins.is_empty(),
+    }
+}
+
+/// Rows are the same when they are about the same entry. Rows without a
+/// dn (eg: the result of GROUP BY) and joined rows are compared on their
+/// values.
+fn row_key(record: &Record, by_values: bool) -> String {
+    if !record.dn.is_empty() && !by_values {
+        return dn::normalize(&record.dn).unwrap_or_else(|_| record.dn.to_lowercase());
+    }
+    let mut values: Vec<String> = record
+        .attrs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k.to_lowercase(), v.join("\n").to_lowercase()))
+        .collect();
+    values.sort();
+    values.join("\n")
+}
+
+/// Merges the results of both sides of a UNION, INTERSECT or EXCEPT.
+fn combine(
+    op: &SetOperator,
+    all: bool,
+    left: Vec<Record>,
+    right: Vec<Record>,
+    by_values: bool,
+) -> Vec<Record> {
+    if *op == SetOperator::Union && all {
+        let mut result = left;
+        result.extend(right);
+        return result;
+    }
+    let row_key = |record: &Record| row_key(record, by_values);
+    let mut right_rows: HashMap<String, Record> = HashMap::new();
+    let mut right_keys = Vec::new();
+    for record in right {
+        let key = row_key(&record);
+        if let Entry::Vacant(entry) = right_rows.entry(key.clone()) {
+            right_keys.push(key);
+            entry.insert(record);
+        }
+    }
+
+    let mut result = Vec::new();
+    match op {
+        SetOperator::Union => {
+            let mut seen = HashSet::new();
+            for mut record in left {
+                let key = row_key(&record);
+                if !seen.insert(key.clone()) {
+                    continue;
+                }
+                // an entry on both sides gets the columns of both
+                if let Some(other) = right_rows.get(&key) {
+                    fill(&mut record, other);
+                }
+                result.push(record);
+            }
+            for key in right_keys {
+                if !seen.contains(&key) {
+                    if let Some(record) = right_rows.remove(&key) {
+                        result.push(record);
+                    }
+                }
+            }
+        }
+        SetOperator::Intersect => {
+            for mut record in left {
+                if let Some(other) = right_rows.get(&row_key(&record)) {
+                    fill(&mut record, other);
+                    result.push(record);
+                }
+            }
+        }
+        SetOperator::Except => {
+            for record in left {
+                if !right_rows.contains_key(&row_key(&record)) {
+                    result.push(record);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Adds the columns of other that record doesn't have yet.
+fn fill(record: &mut Record, other: &Record) {
+    for (key, values) in &other.attrs {
+        if record.get(key).is_empty() {
+            record.attrs.insert(key.to_string(), values.clone());
+        }
+    }
+}
+
+/// Evaluates the SELECT list for every record.
+fn project(query: &SqldapQuery, records: Vec<Record>) -> Result<Vec<Record>> {
+    if !query.group_by.is_empty() {
+        return eval::group(&query.columns, &query.group_by, &records);
+    }
+
+    let mut result = Vec::new();
+    for record in records {
+        let mut row = Record {
+            dn: record.dn.clone(),
+            attrs: HashMap::new(),
+            tables: record.tables.clone(),
+        };
+        if query.wildcard {
+            row.attrs = record.attrs.clone();
+        }
+        for (name, expr) in &query.columns {
+            row.attrs
+                .insert(name.to_string(), eval::eval(expr, &record)?);
+        }
+        result.push(row);
+    }
+    Ok(result)
+}
+
+/// Splits `p.uid = g.memberUid` into the attribute of the joined table
+/// (uid) and the expression on the rows that are already there. The
+/// condition itself is returned too, so the rest of ON can be told apart.
+fn join_key<'a>(on: &'a Expr, alias: &str) -> Option<(String, &'a Expr, &'a Expr)> {
+    for condition in eval::conjuncts(on) {
+        if let Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } = condition
+        {
+            for (inner, outer) in &[(left, right), (right, left)] {
+                if let Expr::CompoundIdentifier(idents) = inner.as_ref() {
+                    if idents.len() == 2
+                        && idents[0].value == alias
+                        && !eval::qualifiers(outer).contains(&Some(alias.to_string()))
+                    {
+                        return Some((idents[1].value.clone(), outer, condition));
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Looks up the entries of a joined table that belong to the rows found
+/// so far, with one search per FILTER_BATCH_SIZE values instead of one
+/// search per row.
+fn join(servers: &mut Servers, rows: Vec<Record>, table: &JoinedTable) -> Result<Found> {
+    let (attr, outer, key_condition) = match join_key(&table.on, &table.alias) {
+        Some(key) => key,
+        None => bail!(
+            "JOIN {} needs an ON condition like {}.attr = other.attr",
+            table.alias,
+            table.alias
+        ),
+    };
+    let by_dn = attr.eq_ignore_ascii_case("dn");
+    let normalize = |value: &str| -> String {
+        if by_dn {
+            dn::normalize(value).unwrap_or_else(|_| value.to_lowercase())
+        } else {
+            value.to_lowercase()
+        }
+    };
+
+    let mut keys = Vec::new();
+    let mut seen = HashSet::new();
+    for row in &rows {
+        for value in eval::eval(outer, row)? {
+            if seen.insert(normalize(&value)) {
+                keys.push(value);
+            }
+        }
+    }
+
+    let conditions: Vec<&Expr> = eval::conjuncts(&table.on)
+        .into_iter()
+        .filter(|c| !std::ptr::eq(*c, key_condition))
+        .collect();
+
+    let mut attrs = table.identifiers.clone();
+    if !by_dn && !attrs.is_empty() && !attrs.iter().any(|a| a.eq_ignore_ascii_case(&attr)) {
+        attrs.retain(|a| a != "1.1");
+        attrs.push(attr.clone());
+    }
+
+    let target = Target {
+        server: &table.server,
+        base: &table.table,
+        scope: table.scope,
+        deref: table.deref,
+        referrals: table.referrals,
+    };
+    let mut found = Found::default();
+    if by_dn {
+        // dn valued attributes (eg: member) are read directly, as long as
+        // they are located within the scope of the joined table
+        let base = normalize(&table.table);
+        for key in &keys {
+            let in_scope = match table.scope {
+                Scope::Base => normalize(key) == base,
+                Scope::OneLevel => {
+                    dn::parent(key, 1).map(|p| normalize(&p)).ok() == Some(base.clone())
+                }
+                Scope::Subtree => {
+                    let key = normalize(key);
+                    key == base || key.ends_with(&format!(",{}", base))
+                }
+            };
+            if !in_scope {
+                continue;
+            }
+            let entry = Target {
+                base: key,
+                scope: Scope::Base,
+                ..target
+            };
+            match search(servers, &entry, &table.filter, attrs.clone()) {
+                Ok(more) => found.append(more),
+                // the entry a member attribute points to can be gone
+                Err(e) if is_no_such_object(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    } else {
+        for chunk in keys.chunks(FILTER_BATCH_SIZE) {
+            let mut filter = String::from("(|");
+            for key in chunk {
+                filter += &format!("({}={})", attr, escape_filter_value(key));
+            }
+            filter += ")";
+            if table.filter != "(objectClass=*)" {
+                filter = format!("(&{}{})", table.filter, filter);
+            }
+            found.append(search(servers, &target, &filter, attrs.clone())?);
+        }
+    }
+
+    let Found {
+        records: found,
+        limited,
+    } = found;
+    let mut lookup: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, record) in found.iter().enumerate() {
+        for value in record.get(&attr) {
+            lookup.entry(normalize(&value)).or_default().push(i);
+        }
+    }
+    let found: Vec<Record> = found
+        .into_iter()
+        .map(|record| record.qualify(&table.alias))
+        .collect();
+
+    let mut result = Vec::new();
+    for row in rows {
+        let mut matched = Vec::new();
+        for value in eval::eval(outer, &row)? {
+            if let Some(indexes) = lookup.get(&normalize(&value)) {
+                for i in indexes {
+                    if !matched.contains(i) {
+                        matched.push(*i);
+                    }
+                }
+            }
+        }
+
+        let mut joined = false;
+        for i in matched {
+            let mut combined = row.clone();
+            combined.merge(&found[i]);
+            // the rest of the ON condition, eg: AND p.uid != 'root'
+            let mut keep = true;
+            for condition in &conditions {
+                keep = keep && eval::matches(condition, &combined)?;
+            }
+            if keep {
+                result.push(combined);
+                joined = true;
+            }
+        }
+        if !joined && table.outer {
+            result.push(row);
+        }
+    }
+
+    Ok(Found {
+        records: result,
+        limited,
+    })
+}
@@ -0,0 +1,402 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The servers configured in sqldap.ini and the connections to them.
+//!
+//! A statement can use tables of several servers (`examplead.people`), a
+//! connection is only opened for the servers a statement actually uses and
+//! is kept open for the following statements.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use ini::Ini;
+use ldap3::{LdapConn, LdapConnSettings, Scope, SearchEntry};
+
+/// A `[server]` section and its `[server.tables]`, `[server.views]` and
+/// `[server.queries]`.
+pub struct Server {
+    pub name: String,
+    pub connection: Option<String>,
+    pub binddn: Option<String>,
+    pub bindpassword: Option<String>,
+    // never, search, find or always
+    pub deref: Option<String>,
+    // ignore, report or follow
+    pub referrals: Option<String>,
+    // maximum number of entries and seconds of a search
+    pub sizelimit: Option<String>,
+    pub timelimit: Option<String>,
+    // statements that change more entries than this ask for confirmation
+    pub confirm: Option<String>,
+    // the directory of the backups of changed entries
+    pub backupdir: Option<String>,
+    // openldap, 389ds or ad, found out from the server when not set
+    pub flavour: Option<String>,
+    pub tables: HashMap<String, String>,
+    // the search scope of a table, eg: people.scope=one
+    pub scopes: HashMap<String, String>,
+    // the rdn attribute of the entries INSERT adds, eg: people.rdn=uid
+    pub rdns: HashMap<String, String>,
+    // SELECT queries that can be used as a table
+    pub views: HashMap<String, String>,
+    pub queries: HashMap<String, String>,
+}
+
+impl Server {
+    fn new(name: &str) -> Server {
+        Server {
+            name: name.to_string(),
+            connection: None,
+            binddn: None,
+            bindpassword: None,
+            deref: None,
+            referrals: None,
+            sizelimit: None,
+            timelimit: None,
+            confirm: None,
+            backupdir: None,
+            flavour: None,
+            tables: HashMap::new(),
+            scopes: HashMap::new(),
+            rdns: HashMap::new(),
+            views: HashMap::new(),
+            queries: HashMap::new(),
+        }
+    }
+}
+
+pub struct Servers {
+    // the server of tables without a server name
+    pub default: String,
+    servers: Vec<Server>,
+    connections: HashMap<String, LdapConn>,
+    flavours: HashMap<String, Flavour>,
+    // referrals returned by searches that were not followed, they are
+    // listed with the results of the statement
+    pub referrals: Vec<String>,
+    pub session: Session,
+}
+
+/// How the results are printed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Table,
+    Csv,
+}
+
+/// The kind of directory server, passwords and account locks are stored
+/// differently on each.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flavour {
+    OpenLdap,
+    Ds389,
+    ActiveDirectory,
+    Other,
+}
+
+/// The variables of SET statements, they apply to the statements that
+/// follow them.
+pub struct Session {
+    // SET sizelimit = n and SET timelimit = n replace the limits of the
+    // servers
+    pub sizelimit: Option<i32>,
+    pub timelimit: Option<i32>,
+    // SET base = 'dc=example,dc=com': FROM "ou=people" searches
+    // ou=people,dc=example,dc=com and a SELECT without FROM searches the base
+    pub base: Option<String>,
+    pub output: Output,
+    // SET subtreedelete = ON: DELETE also removes the entries below the
+    // entries it deletes
+    pub subtree_delete: bool,
+    // BEGIN: the identifier of the LDAP transaction the changes are part of
+    pub transaction: Option<Vec<u8>>,
+    // the values of the :parameters of the query, see params.rs
+    pub parameters: HashMap<String, String>,
+}
+
+impl Servers {
+    pub fn new(conf: &Ini, default: &str) -> Servers {
+        let mut servers: Vec<Server> = Vec::new();
+        for (sec, prop) in conf {
+            let sec = match sec {
+                Some(sec) => sec,
+                None => continue,
+            };
+            let (name, kind) = match sec.rfind('.') {
+                Some(pos) if [".tables", ".views", ".queries"].contains(&&sec[pos..]) => {
+                    (&sec[..pos], &sec[pos + 1..])
+                }
+                _ => (sec, ""),
+            };
+            let pos = match servers.iter().position(|s| s.name == name) {
+                Some(pos) => pos,
+                None => {
+                    servers.push(Server::new(name));
+                    servers.len() - 1
+                }
+            };
+            let server = &mut servers[pos];
+            for (key, value) in prop.iter() {
+                match kind {
+                    "tables" => {
+                        if let Some(table) = key.strip_suffix(".scope") {
+                            server.scopes.insert(table.to_string(), value.to_string());
+                        } else if let Some(table) = key.strip_suffix(".rdn") {
+                            server.rdns.insert(table.to_string(), value.to_string());
+                        } else {
+                            server.tables.insert(key.to_string(), value.to_string());
+                        }
+                    }
+                    "views" => {
+                        server.views.insert(key.to_string(), value.to_string());
+                    }
+                    "queries" => {
+                        server.queries.insert(key.to_string(), value.to_string());
+                    }
+                    _ => match key {
+                        "connection" => server.connection = Some(value.to_string()),
+                        "binddn" => server.binddn = Some(value.to_string()),
+                        "bindpassword" => server.bindpassword = Some(value.to_string()),
+                        "deref" => server.deref = Some(value.to_string()),
+                        "referrals" => server.referrals = Some(value.to_string()),
+                        "sizelimit" => server.sizelimit = Some(value.to_string()),
+                        "timelimit" => server.timelimit = Some(value.to_string()),
+                        "confirm" => server.confirm = Some(value.to_string()),
+                        "backupdir" => server.backupdir = Some(value.to_string()),
+                        "flavour" => server.flavour = Some(value.to_string()),
+                        _ => {}
+                    },
+                }
+            }
+        }
+
+        Servers {
+            default: default.to_string(),
+            servers,
+            connections: HashMap::new(),
+            flavours: HashMap::new(),
+            referrals: Vec::new(),
+            session: Session {
+                sizelimit: None,
+                timelimit: None,
+                base: None,
+                output: Output::Table,
+                subtree_delete: false,
+                transaction: None,
+                parameters: HashMap::new(),
+            },
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Server> {
+        self.servers.iter().find(|s| s.name == name)
+    }
+
+    /// The names of the servers that have a connection configured.
+    pub fn names(&self) -> Vec<String> {
+        self.servers
+            .iter()
+            .filter(|s| s.connection.is_some())
+            .map(|s| s.name.clone())
+            .collect()
+    }
+
+    /// The default server, the one tables without a server name belong to.
+    pub fn current(&self) -> Option<&Server> {
+        self.get(&self.default)
+    }
+
+    /// The sizelimit and timelimit of the searches on a server, 0 is no
+    /// limit (other than the limits of the server itself).
+    pub fn limits(&self, name: &str) -> Result<(i32, i32)> {
+        let server = self.get(name);
+        let sizelimit = match (
+            self.session.sizelimit,
+            server.and_then(|s| s.sizelimit.as_ref()),
+        ) {
+            (Some(limit), _) => limit,
+            (None, Some(limit)) => {
+                parse_limit(limit).context(format!("Wrong sizelimit in section {}", name))?
+            }
+            (None, None) => 0,
+        };
+        let timelimit = match (
+            self.session.timelimit,
+            server.and_then(|s| s.timelimit.as_ref()),
+        ) {
+            (Some(limit), _) => limit,
+            (None, Some(limit)) => {
+                parse_limit(limit).context(format!("Wrong timelimit in section {}", name))?
+            }
+            (None, None) => 0,
+        };
+        Ok((sizelimit, timelimit))
+    }
+
+    /// The number of entries a statement can change on a server without
+    /// asking for confirmation, 1 unless the server has confirm=n.
+    pub fn confirm(&self, name: &str) -> Result<usize> {
+        match self.get(name).and_then(|s| s.confirm.as_ref()) {
+            Some(confirm) => Ok(parse_limit(confirm)
+                .context(format!("Wrong confirm in section {}", name))?
+                as usize),
+            None => Ok(1),
+        }
+    }
+
+    /// The kind of a server: flavour= of its section or else what its root
+    /// DSE tells.
+    pub fn flavour(&mut self, name: &str) -> Result<Flavour> {
+        if let Some(flavour) = self.flavours.get(name) {
+            return Ok(*flavour);
+        }
+        let flavour = match self.get(name).and_then(|s| s.flavour.clone()) {
+            Some(flavour) => {
+                parse_flavour(&flavour).context(format!("Wrong flavour in section {}", name))?
+            }
+            None => {
+                let attrs = vec!["objectClass", "vendorName", "supportedCapabilities"];
+                let (entries, _) = self
+                    .ldap(name)?
+                    .search("", Scope::Base, "(objectClass=*)", attrs)?
+                    .success()
+                    .context("Cannot read the root DSE")?;
+                let root = entries.into_iter().next().map(SearchEntry::construct);
+                let values = |attr: &str| {
+                    root.as_ref()
+                        .and_then(|e| e.attrs.iter().find(|(a, _)| a.eq_ignore_ascii_case(attr)))
+                        .map(|(_, values)| values.clone())
+                        .unwrap_or_default()
+                };
+                // LDAP_CAP_ACTIVE_DIRECTORY_OID
+                if values("supportedCapabilities").contains(&"1.2.840.113556.1.4.800".to_string()) {
+                    Flavour::ActiveDirectory
+                } else if values("vendorName").iter().any(|v| v.contains("389")) {
+                    Flavour::Ds389
+                } else if values("objectClass")
+                    .iter()
+                    .any(|v| v.eq_ignore_ascii_case("OpenLDAProotDSE"))
+                {
+                    Flavour::OpenLdap
+                } else {
+                    Flavour::Other
+                }
+            }
+        };
+        self.flavours.insert(name.to_string(), flavour);
+        Ok(flavour)
+    }
+
+    /// The connection to a server, which is opened (and bound when the
+    /// server has a binddn and bindpassword) the first time it is needed.
+    pub fn ldap(&mut self, name: &str) -> Result<&mut LdapConn> {
+        match self.connections.entry(name.to_string()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let server = self
+                    .servers
+                    .iter()
+                    .find(|s| s.name == name)
+                    .context(format!("Server {} is not configured.", name))?;
+                let connection = server.connection.as_ref().context(format!(
+                    "Key 'connection' in section {} could not be found.",
+                    name
+                ))?;
+                if name != self.default {
+                    println!("Using server: {} ({})\n", name, connection);
+                }
+                Ok(entry.insert(connect(server, connection)?))
+            }
+        }
+    }
+
+    /// The connection to the server a referral of server name points to,
+    /// it is bound with the credentials of server name.
+    pub fn referral(&mut self, name: &str, connection: &str) -> Result<&mut LdapConn> {
+        match self.connections.entry(format!("{} {}", name, connection)) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let server = self
+                    .servers
+                    .iter()
+                    .find(|s| s.name == name)
+                    .context(format!("Server {} is not configured.", name))?;
+                println!("Following referral to {}\n", connection);
+                Ok(entry.insert(connect(server, connection)?))
+            }
+        }
+    }
+
+    /// Closes all connections that were opened.
+    pub fn unbind(&mut self) -> Result<()> {
+        for (_, mut ldap) in self.connections.drain() {
+            ldap.unbind().context("ldap unbind failed")?;
+        }
+        Ok(())
+    }
+}
+
+fn connect(server: &Server, connection: &str) -> Result<LdapConn> {
+    let mut ldap =
+        LdapConn::with_settings(LdapConnSettings::new().set_no_tls_verify(true), connection)
+            .context(format!("Cannot connect to LDAP server {}", connection))?;
+    if let (Some(binddn), Some(bindpassword)) = (&server.binddn, &server.bindpassword) {
+        let _res = ldap
+            .simple_bind(binddn, bindpassword)?
+            .success()
+            .context("ldap simple_bind failed")?;
+    }
+    Ok(ldap)
+}
+
+/// A sizelimit (entries) or timelimit (seconds), 0 means no limit.
+pub fn parse_limit(limit: &str) -> Result<i32> {
+    let limit = limit.trim().parse::<i32>();
+    match limit {
+        Ok(limit) if limit >= 0 => Ok(limit),
+        _ => bail!("A limit should be a number >= 0"),
+    }
+}
+
+/// ON or OFF.
+pub fn parse_switch(value: &str) -> Result<bool> {
+    match value.to_uppercase().as_str() {
+        "ON" | "TRUE" | "1" => Ok(true),
+        "OFF" | "FALSE" | "0" => Ok(false),
+        _ => bail!("Use ON or OFF instead of {}", value),
+    }
+}
+
+/// openldap, 389ds, ad or other.
+pub fn parse_flavour(flavour: &str) -> Result<Flavour> {
+    match flavour.to_lowercase().as_str() {
+        "openldap" => Ok(Flavour::OpenLdap),
+        "389ds" | "389-ds" | "389" => Ok(Flavour::Ds389),
+        "ad" | "activedirectory" => Ok(Flavour::ActiveDirectory),
+        "other" => Ok(Flavour::Other),
+        _ => bail!(
+            "Unknown flavour {}, use openldap, 389ds, ad or other",
+            flavour
+        ),
+    }
+}
+
+/// TABLE or CSV.
+pub fn parse_output(output: &str) -> Result<Output> {
+    match output.to_uppercase().as_str() {
+        "TABLE" => Ok(Output::Table),
+        "CSV" => Ok(Output::Csv),
+        _ => bail!("Unknown output {}, use TABLE or CSV", output),
+    }
+}
@@ -0,0 +1,240 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `BEGIN; ... COMMIT;` around the changes of a script.
+//!
+//! When the server supports LDAP transactions (RFC 5805) the changes are
+//! sent as part of a transaction, the server makes them all at COMMIT or
+//! none of them. Other servers get the changes right away, when one fails
+//! the changes made since BEGIN are undone with the old values of their
+//! backups. That is best effort: others can change the entries in between.
+//!
+//! Passwords set with PASSWORD() or SET PASSWORD are refused inside a
+//! transaction: the Password Modify operation can't be part of one and the
+//! old password isn't backed up, so a rollback couldn't restore it.
+
+use anyhow::{Context, Result};
+use ldap3::exop::Exop;
+use ldap3::result::ExopResult;
+use ldap3::LdapResult;
+
+use crate::backup::Backup;
+use crate::modify;
+use crate::server::Servers;
+
+pub const START_TRANSACTION_OID: &str = "1.3.6.1.1.21.1";
+// the control of the changes that are part of a transaction
+pub const TRANSACTION_SPECIFICATION_OID: &str = "1.3.6.1.1.21.2";
+pub const END_TRANSACTION_OID: &str = "1.3.6.1.1.21.3";
+
+pub struct Transaction {
+    pub server: String,
+    // the identifier the server gave the transaction, None when the server
+    // doesn't support transactions
+    id: Option<Vec<u8>>,
+    // the backup of every statement and which of its changes were made
+    statements: Vec<(Backup, Vec<bool>)>,
+    failed: bool,
+}
+
+/// Starts a transaction on the server, the changes that follow are part of
+/// it until COMMIT or ROLLBACK.
+pub fn begin(servers: &mut Servers, server: &str) -> Result<Transaction> {
+    let ldap = servers.ldap(server)?;
+    let id = match ldap.extended(Exop {
+        name: Some(START_TRANSACTION_OID.to_string()),
+        val: None,
+    }) {
+        Ok(ExopResult(exop, result)) if result.rc == 0 => exop.val,
+        _ => None,
+    };
+    if id.is_some() {
+        println!(
+            "Started a transaction on {}, the changes are made at COMMIT.\n",
+            server
+        );
+    } else {
+        println!(
+            "{} doesn't support LDAP transactions, the changes are made right away and undone when one fails.\n",
+            server
+        );
+    }
+    servers.session.transaction = id.clone();
+    Ok(Transaction {
+        server: server.to_string(),
+        id,
+        statements: Vec::new(),
+        failed: false,
+    })
+}
+
+impl Transaction {
+    /// After a failed change the statements up to COMMIT are skipped.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Adds the changes of a statement, when one of them failed the
+    /// transaction is rolled back.
+    pub fn add(&mut self, servers: &mut Servers, backup: Backup, done: Vec<bool>) -> Result<()> {
+        let failed = done.contains(&false);
+        self.statements.push((backup, done));
+        if failed {
+            print!("A change failed. ");
+            self.fail(servers)?;
+        }
+        Ok(())
+    }
+
+    /// Rolls back the transaction, the statements up to COMMIT are skipped.
+    pub fn fail(&mut self, servers: &mut Servers) -> Result<()> {
+        println!("The transaction is rolled back.\n");
+        self.abort(servers)
+    }
+
+    /// Makes the changes of the transaction, their backups are kept.
+    pub fn commit(mut self, servers: &mut Servers) -> Result<()> {
+        if self.failed {
+            println!("The transaction was rolled back, nothing was committed.\n");
+            return Ok(());
+        }
+        if let Some(id) = self.id.take() {
+            servers.session.transaction = None;
+            let result = end(servers, &self.server, id, true)?;
+            if result.rc != 0 {
+                println!(
+                    "The server didn't commit the transaction, nothing was changed: {}\n",
+                    result
+                );
+                for (backup, _) in self.statements {
+                    backup.discard()?;
+                }
+                return Ok(());
+            }
+        }
+        for (backup, done) in self.statements {
+            backup.finish(&done)?;
+        }
+        println!("Committed.\n");
+        Ok(())
+    }
+
+    /// Undoes the changes of the transaction.
+    pub fn rollback(mut self, servers: &mut Servers) -> Result<()> {
+        if !self.failed {
+            self.abort(servers)?;
+        }
+        println!("Rolled back.\n");
+        Ok(())
+    }
+
+    fn abort(&mut self, servers: &mut Servers) -> Result<()> {
+        self.failed = true;
+        if let Some(id) = self.id.take() {
+            servers.session.transaction = None;
+            let result = end(servers, &self.server, id, false)?;
+            if result.rc != 0 {
+                println!("The server didn't abort the transaction: {}\n", result);
+            }
+            for (backup, _) in self.statements.drain(..) {
+                backup.discard()?;
+            }
+            return Ok(());
+        }
+        // the last statement is undone first
+        for (backup, done) in self.statements.drain(..).rev() {
+            let undo = backup.undo(&done);
+            if undo.is_empty() {
+                backup.discard()?;
+                continue;
+            }
+            let undone = modify::apply(servers, &self.server, undo)?;
+            if undone.contains(&false) {
+                // what couldn't be undone can still be done by hand
+                backup.finish(&done)?;
+            } else {
+                backup.discard()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Commits or aborts the transaction.
+fn end(servers: &mut Servers, server: &str, id: Vec<u8>, commit: bool) -> Result<LdapResult> {
+    let ldap = servers.ldap(server)?;
+    let ExopResult(_, result) = ldap
+        .extended(Exop {
+            name: Some(END_TRANSACTION_OID.to_string()),
+            val: Some(end_request(id, commit)),
+        })
+        .context("Cannot end the transaction")?;
+    Ok(result)
+}
+
+// txnEndReq ::= SEQUENCE { commit BOOLEAN DEFAULT TRUE, identifier OCTET STRING }
+fn end_request(id: Vec<u8>, commit: bool) -> Vec<u8> {
+    let mut inner = Vec::new();
+    if !commit {
+        inner.extend_from_slice(&[0x01, 0x01, 0x00]);
+    }
+    inner.push(0x04);
+    inner.extend(ber_length(id.len()));
+    inner.extend(id);
+    let mut val = vec![0x30];
+    val.extend(ber_length(inner.len()));
+    val.extend(inner);
+    val
+}
+
+fn ber_length(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        return vec![len as u8];
+    }
+    let bytes: Vec<u8> = len
+        .to_be_bytes()
+        .iter()
+        .copied()
+        .skip_while(|b| *b == 0)
+        .collect();
+    let mut encoded = vec![0x80 | bytes.len() as u8];
+    encoded.extend(bytes);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn txn_end_request() {
+        assert_eq!(
+            end_request(vec![1, 2], true),
+            [0x30, 0x04, 0x04, 0x02, 1, 2]
+        );
+        assert_eq!(
+            end_request(vec![1, 2], false),
+            [0x30, 0x07, 0x01, 0x01, 0x00, 0x04, 0x02, 1, 2]
+        );
+        let request = end_request(vec![7; 200], true);
+        assert_eq!(request[..7], [0x30, 0x81, 0xcb, 0x04, 0x81, 0xc8, 7]);
+        assert_eq!(request.len(), 3 + 3 + 200);
+    }
+
+    #[test]
+    fn ber_lengths() {
+        assert_eq!(ber_length(0), [0x00]);
+        assert_eq!(ber_length(0x7f), [0x7f]);
+        assert_eq!(ber_length(0x80), [0x81, 0x80]);
+        assert_eq!(ber_length(0x1234), [0x82, 0x12, 0x34]);
+    }
+}
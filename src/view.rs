@@ -0,0 +1,417 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Views: queries of the `[server.views]` section that can be used as a
+//! table, eg: `lockedpeople=SELECT * FROM @people WHERE passwordretrycount>=3`.
+//!
+//! A view is not searched by itself. `SELECT uid FROM @lockedpeople WHERE
+//! ou=x` is rewritten into a query on the table of the view with both WHERE
+//! clauses, which is then a single search like any other query.
+
+use anyhow::{bail, Context, Result};
+use sqlparser::ast::{
+    BinaryOperator, Expr, Ident, JoinConstraint, JoinOperator, ObjectName, Select, SelectItem,
+    SetExpr, Statement, TableAlias, TableFactor,
+};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+
+use crate::eval;
+use crate::params;
+use crate::server::Servers;
+
+// a view can use another view, but not itself
+const MAX_DEPTH: usize = 10;
+
+/// The parts of a view that end up in the query that uses it.
+struct View {
+    // the table of the view, with the alias of the view in the query
+    relation: TableFactor,
+    // the WHERE clause of the view, with unqualified attributes
+    selection: Option<Expr>,
+    // the columns of the view by name
+    columns: Vec<(String, Expr)>,
+    // SELECT * has all attributes of the table
+    wildcard: bool,
+}
+
+/// Replaces the views in the FROM and JOIN clauses of a select by the
+/// tables they are defined on.
+pub fn expand(mut s: Select, servers: &Servers) -> Result<Select> {
+    for _ in 0..MAX_DEPTH {
+        if s.from.len() != 1 {
+            return Ok(s);
+        }
+        let mut expanded = false;
+
+        if let Some(view) = lookup(&s.from[0].relation, servers)? {
+            let qualify = !s.from[0].joins.is_empty();
+            let alias = view.alias();
+            let columns = |e: &Expr| view.column(e, &alias, qualify);
+
+            s.projection = view.projection(s.projection, &alias, qualify);
+            s.group_by = s.group_by.iter().map(|e| rewrite(e, &columns)).collect();
+            let selection = s.selection.as_ref().map(|e| rewrite(e, &columns));
+            let own = view.selection_as(&alias, qualify);
+            s.selection = and(own, selection);
+            for join in s.from[0].joins.iter_mut() {
+                join.join_operator = on(&join.join_operator, |e| rewrite(e, &columns))?;
+            }
+            s.from[0].relation = view.relation;
+            expanded = true;
+        }
+
+        for i in 0..s.from[0].joins.len() {
+            let view = match lookup(&s.from[0].joins[i].relation, servers)? {
+                Some(view) => view,
+                None => continue,
+            };
+            let alias = view.alias();
+            let columns = |e: &Expr| view.column(e, &alias, true);
+
+            s.projection = view.projection(s.projection, &alias, true);
+            s.group_by = s.group_by.iter().map(|e| rewrite(e, &columns)).collect();
+            s.selection = s.selection.as_ref().map(|e| rewrite(e, &columns));
+            for join in s.from[0].joins.iter_mut() {
+                join.join_operator = on(&join.join_operator, |e| rewrite(e, &columns))?;
+            }
+            // the WHERE clause of the view becomes part of the join condition,
+            // so a LEFT JOIN keeps the rows that have no match in the view
+            let own = view.selection_as(&alias, true);
+            let join = &mut s.from[0].joins[i];
+            join.join_operator = on(&join.join_operator, |e| {
+                and(Some(e.clone()), own.clone()).unwrap_or_else(|| e.clone())
+            })?;
+            join.relation = view.relation;
+            expanded = true;
+        }
+
+        if !expanded {
+            return Ok(s);
+        }
+    }
+    bail!("Views are nested too deep, does a view refer to itself?")
+}
+
+/// The view a table refers to: `@view` on the default server or
+/// `server.view`. A table of the .tables section hides a view with the
+/// same name.
+fn lookup(relation: &TableFactor, servers: &Servers) -> Result<Option<View>> {
+    let (name, alias, args, hints) = match relation {
+        TableFactor::Table {
+            name,
+            alias,
+            args,
+            with_hints,
+        } => (name, alias, args, with_hints),
+        _ => return Ok(None),
+    };
+    let (server, view) = match name.0.as_slice() {
+        [view] if view.value.starts_with('@') => (servers.default.as_str(), &view.value[1..]),
+        [server, view] if servers.get(&server.value).is_some() => {
+            (server.value.as_str(), view.value.trim_start_matches('@'))
+        }
+        _ => return Ok(None),
+    };
+    let sql = match servers.get(server) {
+        Some(s) if !s.tables.contains_key(view) => match s.views.get(view) {
+            Some(sql) => sql,
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    if !args.is_empty() || !hints.is_empty() {
+        bail!(
+            "{} is a view, table functions and WITH (...) can only be used in the view itself",
+            view
+        );
+    }
+
+    let statements = Parser::parse_sql(&GenericDialect {}, sql).context(format!(
+        "Cannot parse view {} in section {}.views",
+        view, server
+    ))?;
+    let select = match statements.as_slice() {
+        [Statement::Query(q)] if q.ctes.is_empty() && q.order_by.is_empty() => match &q.body {
+            SetExpr::Select(s) => Some(s.as_ref().clone()),
+            _ => None,
+        },
+        _ => None,
+    };
+    let select = match select {
+        Some(s) if s.from.len() == 1 && s.from[0].joins.is_empty() && s.group_by.is_empty() => s,
+        _ => bail!(
+            "View {} in section {}.views should be a SELECT on one table, without JOIN or GROUP BY",
+            view,
+            server
+        ),
+    };
+
+    let mut from = select.from.into_iter().next().unwrap().relation;
+    // the alias the view itself uses for its table, eg: FROM @people p
+    let own_alias = match &mut from {
+        TableFactor::Table { name, alias: a, .. } => {
+            // a view of examplead uses the tables of examplead, also when it
+            // is used from a query on another server
+            if server != servers.default {
+                if let [table] = name.0.as_slice() {
+                    if table.value.starts_with('@') {
+                        *name = ObjectName(vec![Ident::new(server), table.clone()]);
+                    }
+                }
+            }
+            let own = a.as_ref().map(|a| a.name.value.clone());
+            *a = Some(TableAlias {
+                name: Ident::new(
+                    alias
+                        .as_ref()
+                        .map(|a| a.name.value.clone())
+                        .unwrap_or_else(|| view.to_string()),
+                ),
+                columns: Vec::new(),
+            });
+            own
+        }
+        _ => bail!(
+            "View {} in section {}.views should select from a table",
+            view,
+            server
+        ),
+    };
+    let unq = |e: &Expr| match &own_alias {
+        Some(a) => eval::unqualify(e, a),
+        None => e.clone(),
+    };
+
+    let mut columns = Vec::new();
+    let mut wildcard = false;
+    for item in select.projection {
+        match item {
+            SelectItem::UnnamedExpr(expr) => {
+                let name = match &expr {
+                    Expr::Identifier(ident) => ident.value.clone(),
+                    Expr::CompoundIdentifier(idents) => {
+                        idents.last().map(|i| i.value.clone()).unwrap_or_default()
+                    }
+                    _ => expr.to_string(),
+                };
+                columns.push((name, unq(&expr)));
+            }
+            SelectItem::ExprWithAlias { expr, alias } => columns.push((alias.value, unq(&expr))),
+            SelectItem::Wildcard => wildcard = true,
+            _ => bail!("Unsupported SelectItem in view {}", view),
+        }
+    }
+
+    Ok(Some(View {
+        relation: from,
+        selection: select.selection.as_ref().map(unq),
+        columns,
+        wildcard,
+    }))
+}
+
+impl View {
+    // the alias of the view in the query, or the name of the view
+    fn alias(&self) -> String {
+        match &self.relation {
+            TableFactor::Table { alias: Some(a), .. } => a.name.value.clone(),
+            _ => String::new(),
+        }
+    }
+
+    /// The WHERE clause of the view, qualified with the alias when the
+    /// query has joins.
+    fn selection_as(&self, alias: &str, qualify: bool) -> Option<Expr> {
+        self.selection
+            .as_ref()
+            .map(|e| qualify_as(e, alias, qualify))
+    }
+
+    /// The expression of a column of the view a query refers to, None when
+    /// the view has no such column.
+    fn column(&self, expr: &Expr, alias: &str, qualify: bool) -> Option<Expr> {
+        let name = match expr {
+            Expr::Identifier(ident) if !qualify && !params::is_value(ident) => &ident.value,
+            Expr::CompoundIdentifier(idents) if idents.len() == 2 && idents[0].value == alias => {
+                &idents[1].value
+            }
+            _ => return None,
+        };
+        let (_, column) = self
+            .columns
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))?;
+        Some(qualify_as(column, alias, qualify))
+    }
+
+    /// The SELECT list of a query with the columns of the view replaced by
+    /// their expressions in the view.
+    fn projection(
+        &self,
+        projection: Vec<SelectItem>,
+        alias: &str,
+        qualify: bool,
+    ) -> Vec<SelectItem> {
+        let column = |e: &Expr| self.column(e, alias, qualify);
+        let mut result = Vec::new();
+        for item in projection {
+            match item {
+                SelectItem::UnnamedExpr(expr) => match (&expr, column(&expr)) {
+                    (Expr::Identifier(ident), Some(e)) => result.push(SelectItem::ExprWithAlias {
+                        expr: e,
+                        alias: ident.clone(),
+                    }),
+                    (Expr::CompoundIdentifier(idents), Some(e)) => {
+                        result.push(SelectItem::ExprWithAlias {
+                            expr: e,
+                            alias: idents[1].clone(),
+                        })
+                    }
+                    _ => result.push(SelectItem::UnnamedExpr(rewrite(&expr, &column))),
+                },
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    result.push(SelectItem::ExprWithAlias {
+                        expr: rewrite(&expr, &column),
+                        alias,
+                    })
+                }
+                // SELECT * of a view is the SELECT list of the view
+                SelectItem::Wildcard | SelectItem::QualifiedWildcard(_)
+                    if !qualify || is_alias(&item, alias) =>
+                {
+                    if self.wildcard {
+                        result.push(SelectItem::Wildcard);
+                    }
+                    for (name, expr) in &self.columns {
+                        result.push(SelectItem::ExprWithAlias {
+                            expr: qualify_as(expr, alias, qualify),
+                            alias: Ident::new(name),
+                        });
+                    }
+                }
+                item => result.push(item),
+            }
+        }
+        result
+    }
+}
+
+fn is_alias(item: &SelectItem, alias: &str) -> bool {
+    match item {
+        SelectItem::QualifiedWildcard(name) => name.0.len() == 1 && name.0[0].value == alias,
+        _ => false,
+    }
+}
+
+// p.uid for uid, when the query has joins
+fn qualify_as(expr: &Expr, alias: &str, qualify: bool) -> Expr {
+    if !qualify {
+        return expr.clone();
+    }
+    rewrite(expr, &|e| match e {
+        Expr::Identifier(ident) => Some(Expr::CompoundIdentifier(vec![
+            Ident::new(alias),
+            ident.clone(),
+        ])),
+        _ => None,
+    })
+}
+
+fn and(left: Option<Expr>, right: Option<Expr>) -> Option<Expr> {
+    // a AND b AND c has to be written as a AND (b AND c) for the filter
+    let nested = |e: Expr| match e {
+        Expr::BinaryOp {
+            op: BinaryOperator::And,
+            ..
+        }
+        | Expr::BinaryOp {
+            op: BinaryOperator::Or,
+            ..
+        } => Box::new(Expr::Nested(Box::new(e))),
+        _ => Box::new(e),
+    };
+    match (left, right) {
+        (Some(l), Some(r)) => Some(Expr::BinaryOp {
+            left: nested(l),
+            op: BinaryOperator::And,
+            right: nested(r),
+        }),
+        (l, r) => l.or(r),
+    }
+}
+
+/// Applies f to the ON condition of a join.
+fn on(operator: &JoinOperator, f: impl Fn(&Expr) -> Expr) -> Result<JoinOperator> {
+    Ok(match operator {
+        JoinOperator::Inner(JoinConstraint::On(e)) => JoinOperator::Inner(JoinConstraint::On(f(e))),
+        JoinOperator::LeftOuter(JoinConstraint::On(e)) => {
+            JoinOperator::LeftOuter(JoinConstraint::On(f(e)))
+        }
+        _ => bail!("Only JOIN ... ON and LEFT JOIN ... ON are supported"),
+    })
+}
+
+/// Replaces the attributes in an expression for which f returns Some. In
+/// `cn=admins` the bare word on the right is a value, not an attribute, so
+/// it is left alone.
+fn rewrite(expr: &Expr, f: &dyn Fn(&Expr) -> Option<Expr>) -> Expr {
+    let rw = |e: &Expr| Box::new(rewrite(e, f));
+    match expr {
+        Expr::Identifier(_) | Expr::CompoundIdentifier(_) => {
+            f(expr).unwrap_or_else(|| expr.clone())
+        }
+        Expr::BinaryOp { left, op, right } => {
+            let right = match (op, right.as_ref()) {
+                (BinaryOperator::And, _) | (BinaryOperator::Or, _) => rw(right),
+                (_, Expr::Identifier(_)) => right.clone(),
+                _ => rw(right),
+            };
+            Expr::BinaryOp {
+                left: rw(left),
+                op: op.clone(),
+                right,
+            }
+        }
+        Expr::UnaryOp { op, expr } => Expr::UnaryOp {
+            op: op.clone(),
+            expr: rw(expr),
+        },
+        Expr::Nested(expr) => Expr::Nested(rw(expr)),
+        Expr::IsNull(expr) => Expr::IsNull(rw(expr)),
+        Expr::IsNotNull(expr) => Expr::IsNotNull(rw(expr)),
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => Expr::InList {
+            expr: rw(expr),
+            list: list.clone(),
+            negated: *negated,
+        },
+        Expr::InSubquery {
+            expr,
+            subquery,
+            negated,
+        } => Expr::InSubquery {
+            expr: rw(expr),
+            subquery: subquery.clone(),
+            negated: *negated,
+        },
+        Expr::Function(function) => {
+            let mut function = function.clone();
+            function.args = function.args.iter().map(|a| rewrite(a, f)).collect();
+            Expr::Function(function)
+        }
+        _ => expr.clone(),
+    }
+}